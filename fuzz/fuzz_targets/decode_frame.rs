@@ -0,0 +1,35 @@
+#![no_main]
+
+// The decoders have no dependency on the rest of the crate, build them alone.
+#[allow(dead_code)]
+#[path = "../../src/citaprotocol.rs"]
+mod citaprotocol;
+#[allow(dead_code)]
+#[path = "../../src/compression.rs"]
+mod compression;
+
+use bytes::BytesMut;
+use citaprotocol::{decode_network_message, DecodeError, MAX_FRAME_LENGTH};
+use compression::decode_message;
+use libfuzzer_sys::fuzz_target;
+
+// Decode the input as the transfer protocol decodes a transport message, the decoders
+// must not panic, and must take every frame they decode or fail on.
+fuzz_target!(|data: &[u8]| {
+    let mut buf = BytesMut::from(data);
+    loop {
+        let len = buf.len();
+        match decode_network_message(&mut buf) {
+            Ok(Some(msg)) => {
+                assert!(buf.len() < len);
+                if let Ok((_, body)) = decode_message(msg, MAX_FRAME_LENGTH) {
+                    assert!(body.len() <= MAX_FRAME_LENGTH);
+                }
+            }
+            Ok(None) => break,
+            // The rest of the transport message is dropped.
+            Err(DecodeError::BadMagic) | Err(DecodeError::Oversize(_)) => break,
+            Err(_) => assert!(buf.len() < len),
+        }
+    }
+});
@@ -0,0 +1,250 @@
+use rand::Rng;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// At most this number of addresses in a bucket are selected by default, so the nodes
+/// in one subnet can not take all the sessions of this node.
+pub const DEFAULT_MAX_ADDRS_PER_BUCKET: usize = 2;
+
+/// Addresses in the same /16 (IPv4) or /32 (IPv6) subnet are in the same bucket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Bucket {
+    V4([u8; 2]),
+    V6([u8; 4]),
+}
+
+pub fn bucket(ip: &IpAddr) -> Bucket {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            Bucket::V4([octets[0], octets[1]])
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4() {
+            // IPv4-mapped and IPv4-compatible addresses
+            Some(ip) if ip.octets()[0] != 0 => bucket(&IpAddr::V4(ip)),
+            _ => {
+                let octets = ip.octets();
+                Bucket::V6([octets[0], octets[1], octets[2], octets[3]])
+            }
+        },
+    }
+}
+
+fn is_local_v4(ip: &Ipv4Addr) -> bool {
+    ip.is_loopback() || ip.is_private() || ip.is_link_local()
+}
+
+fn is_local_v6(ip: &Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    ip.is_loopback()
+        // Unique local, fc00::/7
+        || first & 0xfe00 == 0xfc00
+        // Link-local, fe80::/10
+        || first & 0xffc0 == 0xfe80
+}
+
+/// Loopback, private and link-local addresses, such as the nodes of a private network
+/// or a test cluster on one host. They are not limited per bucket.
+pub fn is_local(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_local_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4() {
+            Some(ip4) if ip4.octets()[0] != 0 => is_local_v4(&ip4),
+            _ => is_local_v6(ip),
+        },
+    }
+}
+
+/// Select up to `n` addresses from the candidates and their scores, randomly and
+/// weighted by score, at most `max_per_bucket` in a bucket except the local addresses.
+/// The addresses in `occupied`, such as the connected ones, count towards the bucket
+/// limit too.
+pub fn select_addrs<R: Rng>(
+    candidates: &[(SocketAddr, i32)],
+    n: usize,
+    max_per_bucket: usize,
+    occupied: &[IpAddr],
+    rng: &mut R,
+) -> Vec<SocketAddr> {
+    let mut bucket_counts: HashMap<Bucket, usize> = HashMap::new();
+    for ip in occupied {
+        *bucket_counts.entry(bucket(ip)).or_insert(0) += 1;
+    }
+
+    // Weighted random order (Efraimidis-Spirakis): sort by u ^ (1 / weight).
+    let mut keyed: Vec<(f64, SocketAddr)> = candidates
+        .iter()
+        .map(|(addr, score)| {
+            let weight = f64::from((*score).max(1));
+            (rng.next_f64().powf(1.0 / weight), *addr)
+        })
+        .collect();
+    keyed.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+
+    let mut selected = Vec::new();
+    for (_, addr) in keyed {
+        if selected.len() >= n {
+            break;
+        }
+        if is_local(&addr.ip()) {
+            selected.push(addr);
+            continue;
+        }
+        let count = bucket_counts.entry(bucket(&addr.ip())).or_insert(0);
+        if *count < max_per_bucket {
+            *count += 1;
+            selected.push(addr);
+        }
+    }
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bucket, is_local, select_addrs, DEFAULT_MAX_ADDRS_PER_BUCKET};
+    use rand::thread_rng;
+    use std::collections::{HashMap, HashSet};
+    use std::net::{IpAddr, SocketAddr};
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn buckets() {
+        assert_eq!(bucket(&ip("10.0.1.1")), bucket(&ip("10.0.200.3")));
+        assert_ne!(bucket(&ip("10.0.1.1")), bucket(&ip("10.1.1.1")));
+        assert_eq!(bucket(&ip("2001:db8::1")), bucket(&ip("2001:db8:ffff::1")));
+        assert_ne!(bucket(&ip("2001:db8::1")), bucket(&ip("2001:db9::1")));
+        assert_eq!(bucket(&ip("::ffff:10.0.1.1")), bucket(&ip("10.0.2.2")));
+    }
+
+    #[test]
+    fn select_randomly() {
+        let candidates: Vec<(SocketAddr, i32)> = (1..=10)
+            .map(|i| (addr(&format!("10.{}.0.1:4000", i)), 100))
+            .collect();
+        let mut rng = thread_rng();
+
+        let mut counts: HashMap<SocketAddr, usize> = HashMap::new();
+        let mut results = HashSet::new();
+        for _ in 0..2000 {
+            let selected =
+                select_addrs(&candidates, 3, DEFAULT_MAX_ADDRS_PER_BUCKET, &[], &mut rng);
+            assert_eq!(selected.len(), 3);
+            for addr in &selected {
+                *counts.entry(*addr).or_insert(0) += 1;
+            }
+            results.insert(selected);
+        }
+
+        // Every address is selected about 2000 * 3 / 10 = 600 times.
+        assert_eq!(counts.len(), 10);
+        for count in counts.values() {
+            assert!(*count > 450 && *count < 750, "count: {}", count);
+        }
+        assert!(results.len() > 100);
+    }
+
+    #[test]
+    fn select_weighted_by_score() {
+        let high = addr("10.1.0.1:4000");
+        let low = addr("10.2.0.1:4000");
+        let candidates = vec![(high, 150), (low, 50)];
+        let mut rng = thread_rng();
+
+        let high_count = (0..10000)
+            .filter(|_| {
+                select_addrs(&candidates, 1, DEFAULT_MAX_ADDRS_PER_BUCKET, &[], &mut rng)
+                    == vec![high]
+            })
+            .count();
+        // P(high) = 150 / (150 + 50)
+        assert!(
+            high_count > 7200 && high_count < 7800,
+            "count: {}",
+            high_count
+        );
+
+        // Addresses with zero score can still be selected.
+        let candidates = vec![(high, 0)];
+        assert_eq!(
+            select_addrs(&candidates, 1, DEFAULT_MAX_ADDRS_PER_BUCKET, &[], &mut rng),
+            vec![high]
+        );
+    }
+
+    #[test]
+    fn limit_per_bucket() {
+        let mut candidates: Vec<(SocketAddr, i32)> = (1..=20)
+            .map(|i| (addr(&format!("20.0.{}.1:4000", i)), 200))
+            .collect();
+        candidates.push((addr("20.1.0.1:4000"), 1));
+        candidates.push((addr("[2001:db8::1]:4000"), 1));
+        candidates.push((addr("[2001:db8:1::1]:4000"), 1));
+        candidates.push((addr("[2001:db8:2::1]:4000"), 1));
+        let mut rng = thread_rng();
+
+        for max_per_bucket in 1..4 {
+            for _ in 0..100 {
+                let selected = select_addrs(&candidates, 10, max_per_bucket, &[], &mut rng);
+                let flooded = selected
+                    .iter()
+                    .filter(|addr| bucket(&addr.ip()) == bucket(&ip("20.0.0.0")))
+                    .count();
+                assert_eq!(flooded, max_per_bucket);
+                assert_eq!(selected.len(), max_per_bucket * 2 + 1);
+            }
+        }
+
+        // The occupied addresses count too.
+        let selected = select_addrs(
+            &candidates,
+            10,
+            DEFAULT_MAX_ADDRS_PER_BUCKET,
+            &[ip("20.0.0.9"), ip("20.0.0.8")],
+            &mut rng,
+        );
+        assert!(selected
+            .iter()
+            .all(|addr| bucket(&addr.ip()) != bucket(&ip("20.0.0.0"))));
+    }
+
+    #[test]
+    fn local_addrs_not_limited() {
+        for local in &[
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "172.31.255.1",
+            "192.168.1.1",
+            "169.254.0.1",
+            "::1",
+            "fc00::1",
+            "fd12:3456::1",
+            "fe80::1",
+            "::ffff:192.168.1.1",
+        ] {
+            assert!(is_local(&ip(local)), "{}", local);
+        }
+        for public in &["8.8.8.8", "172.32.0.1", "2001:db8::1", "::ffff:8.8.8.8"] {
+            assert!(!is_local(&ip(public)), "{}", public);
+        }
+
+        let candidates: Vec<(SocketAddr, i32)> = (1..=10)
+            .map(|i| (addr(&format!("192.168.0.{}:4000", i)), 100))
+            .collect();
+        let selected = select_addrs(
+            &candidates,
+            10,
+            DEFAULT_MAX_ADDRS_PER_BUCKET,
+            &[ip("192.168.0.100")],
+            &mut thread_rng(),
+        );
+        assert_eq!(selected.len(), 10);
+    }
+}
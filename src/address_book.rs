@@ -0,0 +1,173 @@
+use crate::persist::{from_unix_secs, to_unix_secs, write_toml_file};
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+pub const ADDRESS_BOOK_FILE: &str = "address_book.toml";
+pub const DEFAULT_SCORE: i32 = 100;
+
+/// What we know about a node address.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AddrInfo {
+    pub score: i32,
+    /// The last time the address is heard from discovery or connected.
+    pub last_seen: Option<SystemTime>,
+    /// The last time a session to the address was opened.
+    pub last_success: Option<SystemTime>,
+    /// Dial failures since the last success.
+    pub failure_count: u32,
+}
+
+impl Default for AddrInfo {
+    fn default() -> Self {
+        AddrInfo {
+            score: DEFAULT_SCORE,
+            last_seen: None,
+            last_success: None,
+            failure_count: 0,
+        }
+    }
+}
+
+impl AddrInfo {
+    pub fn seen(&mut self) {
+        self.last_seen = Some(SystemTime::now());
+    }
+
+    pub fn connected(&mut self) {
+        let now = SystemTime::now();
+        self.last_seen = Some(now);
+        self.last_success = Some(now);
+        self.failure_count = 0;
+    }
+}
+
+// The layout of the address book file.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct AddressBookFile {
+    #[serde(default)]
+    addrs: Vec<AddrRecord>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct AddrRecord {
+    addr: String,
+    score: i32,
+    // Seconds since the unix epoch.
+    last_seen: Option<u64>,
+    last_success: Option<u64>,
+    #[serde(default)]
+    failure_count: u32,
+}
+
+/// Load the address book, return an empty one if the file does not exist or is broken.
+pub fn load_address_book(path: &Path) -> Vec<(SocketAddr, AddrInfo)> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Vec::new(),
+        Err(err) => {
+            warn!("[AddressBook] Read {:?} failed : {:?}", path, err);
+            return Vec::new();
+        }
+    };
+    let file: AddressBookFile = match toml::from_str(&content) {
+        Ok(file) => file,
+        Err(err) => {
+            warn!("[AddressBook] Parse {:?} failed : {}", path, err);
+            return Vec::new();
+        }
+    };
+
+    let addrs: Vec<(SocketAddr, AddrInfo)> = file
+        .addrs
+        .into_iter()
+        .filter_map(|record| match SocketAddr::from_str(&record.addr) {
+            Ok(addr) => Some((
+                addr,
+                AddrInfo {
+                    score: record.score,
+                    last_seen: record.last_seen.map(from_unix_secs),
+                    last_success: record.last_success.map(from_unix_secs),
+                    failure_count: record.failure_count,
+                },
+            )),
+            Err(_) => {
+                warn!("[AddressBook] Ignore invalid address {:?}", record.addr);
+                None
+            }
+        })
+        .collect();
+    info!(
+        "[AddressBook] Load {} addresses from {:?}",
+        addrs.len(),
+        path
+    );
+
+    addrs
+}
+
+pub fn save_address_book<'a, I>(path: &Path, addrs: I) -> io::Result<()>
+where
+    I: IntoIterator<Item = (SocketAddr, &'a AddrInfo)>,
+{
+    let addrs = addrs
+        .into_iter()
+        .map(|(addr, info)| AddrRecord {
+            addr: addr.to_string(),
+            score: info.score,
+            last_seen: info.last_seen.map(to_unix_secs),
+            last_success: info.last_success.map(to_unix_secs),
+            failure_count: info.failure_count,
+        })
+        .collect();
+
+    write_toml_file(path, &AddressBookFile { addrs })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load_address_book, save_address_book, AddrInfo};
+    use crate::persist::from_unix_secs;
+    use std::fs;
+    use std::net::SocketAddr;
+    use tempfile::tempdir;
+
+    #[test]
+    fn save_and_load() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("address_book.toml");
+        assert!(load_address_book(&path).is_empty());
+
+        let addr1: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let addr2: SocketAddr = "[::1]:4001".parse().unwrap();
+        let info1 = AddrInfo {
+            score: 80,
+            last_seen: Some(from_unix_secs(1_500_000_000)),
+            last_success: None,
+            failure_count: 3,
+        };
+        let info2 = AddrInfo {
+            score: 120,
+            last_seen: Some(from_unix_secs(1_500_000_100)),
+            last_success: Some(from_unix_secs(1_500_000_100)),
+            failure_count: 0,
+        };
+        save_address_book(&path, vec![(addr1, &info1), (addr2, &info2)]).unwrap();
+
+        let addrs = load_address_book(&path);
+        assert_eq!(addrs, vec![(addr1, info1), (addr2, info2)]);
+    }
+
+    #[test]
+    fn load_broken_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("address_book.toml");
+        fs::write(&path, "addrs = 1").unwrap();
+        assert!(load_address_book(&path).is_empty());
+    }
+}
@@ -0,0 +1,157 @@
+use log::{info, warn};
+use std::collections::HashSet;
+use std::fs;
+use std::time::SystemTime;
+
+/// Node public keys allowed to open a session with us.
+///
+/// Keys come from `allowed_keys` in the config and from an optional `allowed_keys_file`,
+/// which holds one hex encoded key per line and is reloaded when it changes.
+/// If neither is configured, every node is allowed.
+#[derive(Debug, Default)]
+pub struct Allowlist {
+    config_keys: HashSet<String>,
+    file: Option<String>,
+    file_keys: HashSet<String>,
+    // Modified time and length of the file when it was loaded.
+    file_stamp: Option<(SystemTime, u64)>,
+}
+
+impl Allowlist {
+    pub fn new(keys: Vec<String>, file: Option<String>) -> Self {
+        let mut allowlist = Allowlist {
+            config_keys: keys.iter().map(|key| normalize_key(key)).collect(),
+            file,
+            file_keys: HashSet::new(),
+            file_stamp: None,
+        };
+        allowlist.reload_if_changed();
+        allowlist
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.config_keys.is_empty() || self.file.is_some()
+    }
+
+    pub fn is_allowed(&self, public_key: Option<&str>) -> bool {
+        if !self.is_enabled() {
+            return true;
+        }
+        match public_key {
+            Some(key) => {
+                let key = normalize_key(key);
+                self.config_keys.contains(&key) || self.file_keys.contains(&key)
+            }
+            None => false,
+        }
+    }
+
+    pub fn set_config_keys(&mut self, keys: Vec<String>) {
+        self.config_keys = keys.iter().map(|key| normalize_key(key)).collect();
+    }
+
+    /// Reload the allowlist file if it has been modified since last load.
+    /// If the file cannot be read, keep the keys loaded last time.
+    pub fn reload_if_changed(&mut self) {
+        let path = match self.file {
+            Some(ref path) => path,
+            None => return,
+        };
+
+        let stamp = fs::metadata(path)
+            .and_then(|metadata| Ok((metadata.modified()?, metadata.len())))
+            .ok();
+        if stamp.is_some() && stamp == self.file_stamp {
+            return;
+        }
+
+        match fs::read_to_string(path) {
+            Ok(content) => {
+                self.file_keys = parse_keys(&content);
+                self.file_stamp = stamp;
+                info!(
+                    "[Allowlist] Load {} keys from {}",
+                    self.file_keys.len(),
+                    path
+                );
+            }
+            Err(err) => {
+                warn!(
+                    "[Allowlist] Read allowlist file {} failed : {:?}",
+                    path, err
+                );
+            }
+        }
+    }
+}
+
+fn parse_keys(content: &str) -> HashSet<String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(normalize_key)
+        .collect()
+}
+
+/// Hex encoded public keys are compared without the "0x" prefix, in lowercase.
+pub fn normalize_key(key: &str) -> String {
+    let key = key.trim();
+    let key = if key.starts_with("0x") || key.starts_with("0X") {
+        &key[2..]
+    } else {
+        key
+    };
+    key.to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Allowlist;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn allow_all_without_config() {
+        let allowlist = Allowlist::new(Vec::new(), None);
+        assert!(!allowlist.is_enabled());
+        assert!(allowlist.is_allowed(Some("02aa")));
+        assert!(allowlist.is_allowed(None));
+    }
+
+    #[test]
+    fn allow_config_keys() {
+        let allowlist = Allowlist::new(vec!["0x02AA".to_owned()], None);
+        assert!(allowlist.is_allowed(Some("02aa")));
+        assert!(allowlist.is_allowed(Some("02AA")));
+        assert!(!allowlist.is_allowed(Some("02bb")));
+        assert!(!allowlist.is_allowed(None));
+    }
+
+    #[test]
+    fn reload_file_keys() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("allowlist");
+        fs::write(&path, "# validators\n02aa\n\n02bb\n").unwrap();
+
+        let mut allowlist = Allowlist::new(
+            vec!["02cc".to_owned()],
+            Some(path.to_str().unwrap().to_owned()),
+        );
+        assert!(allowlist.is_allowed(Some("02aa")));
+        assert!(allowlist.is_allowed(Some("02bb")));
+        assert!(allowlist.is_allowed(Some("02cc")));
+        assert!(!allowlist.is_allowed(Some("02dd")));
+
+        fs::write(&path, "02dd\n").unwrap();
+        allowlist.reload_if_changed();
+        assert!(!allowlist.is_allowed(Some("02aa")));
+        assert!(allowlist.is_allowed(Some("02cc")));
+        assert!(allowlist.is_allowed(Some("02dd")));
+
+        // Keep the keys if the file is gone.
+        fs::remove_file(&path).unwrap();
+        allowlist.reload_if_changed();
+        assert!(allowlist.is_allowed(Some("02dd")));
+    }
+}
@@ -0,0 +1,401 @@
+use crate::allowlist::normalize_key;
+use crate::persist::{from_unix_secs, to_unix_secs, write_toml_file};
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+pub const BAN_LIST_FILE: &str = "banned_peers.toml";
+
+/// What is banned, written as "ip:1.2.3.4", "subnet:10.0.0.0/8" or "key:<hex public key>".
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum BanTarget {
+    Ip(IpAddr),
+    Subnet(IpAddr, u8),
+    PublicKey(String),
+}
+
+impl BanTarget {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match self {
+            BanTarget::Ip(banned) => banned == ip,
+            BanTarget::Subnet(network, prefix) => in_subnet(network, *prefix, ip),
+            BanTarget::PublicKey(_) => false,
+        }
+    }
+
+    // Public keys are stored normalized, so a key matches however it is written.
+    fn normalized(&self) -> BanTarget {
+        match self {
+            BanTarget::PublicKey(key) => BanTarget::PublicKey(normalize_key(key)),
+            target => target.clone(),
+        }
+    }
+}
+
+impl fmt::Display for BanTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BanTarget::Ip(ip) => write!(f, "ip:{}", ip),
+            BanTarget::Subnet(network, prefix) => write!(f, "subnet:{}/{}", network, prefix),
+            BanTarget::PublicKey(key) => write!(f, "key:{}", key),
+        }
+    }
+}
+
+impl FromStr for BanTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        let (ty, value) = match (parts.next(), parts.next()) {
+            (Some(ty), Some(value)) => (ty, value.trim()),
+            _ => return Err(format!("invalid ban target {:?}", s)),
+        };
+
+        match ty {
+            "ip" => IpAddr::from_str(value)
+                .map(BanTarget::Ip)
+                .map_err(|err| format!("invalid ip {:?}: {}", value, err)),
+            "subnet" => {
+                let mut parts = value.splitn(2, '/');
+                let network = parts
+                    .next()
+                    .and_then(|network| IpAddr::from_str(network).ok());
+                let prefix = parts.next().and_then(|prefix| u8::from_str(prefix).ok());
+                match (network, prefix) {
+                    (Some(network), Some(prefix)) if prefix <= max_prefix(&network) => {
+                        Ok(BanTarget::Subnet(network, prefix))
+                    }
+                    _ => Err(format!("invalid subnet {:?}", value)),
+                }
+            }
+            "key" if !normalize_key(value).is_empty() => {
+                Ok(BanTarget::PublicKey(normalize_key(value)))
+            }
+            _ => Err(format!("invalid ban target {:?}", s)),
+        }
+    }
+}
+
+fn max_prefix(ip: &IpAddr) -> u8 {
+    match ip {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn in_subnet(network: &IpAddr, prefix: u8, ip: &IpAddr) -> bool {
+    match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            let mask = if prefix == 0 {
+                0
+            } else {
+                u32::max_value() << (32 - u32::from(prefix))
+            };
+            u32::from(*network) & mask == u32::from(*ip) & mask
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => {
+            let mask = if prefix == 0 {
+                0
+            } else {
+                u128::max_value() << (128 - u32::from(prefix))
+            };
+            u128::from(*network) & mask == u128::from(*ip) & mask
+        }
+        _ => false,
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BanEntry {
+    /// None means banned forever.
+    pub expires_at: Option<SystemTime>,
+    pub reason: String,
+}
+
+impl BanEntry {
+    fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at.map(|at| at <= now).unwrap_or(false)
+    }
+}
+
+// The layout of the ban list file.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct BanListFile {
+    #[serde(default)]
+    bans: Vec<BanRecord>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct BanRecord {
+    target: String,
+    // Seconds since the unix epoch.
+    expires_at: Option<u64>,
+    #[serde(default)]
+    reason: String,
+}
+
+/// Banned IPs, subnets and public keys, saved to a file if a path is given.
+#[derive(Debug, Default)]
+pub struct BanList {
+    entries: HashMap<BanTarget, BanEntry>,
+    path: Option<PathBuf>,
+}
+
+impl BanList {
+    /// Load the ban list from `path`, start with an empty one if the file does not exist.
+    pub fn load<P: AsRef<Path>>(path: P) -> Self {
+        let path = path.as_ref();
+        let mut ban_list = BanList {
+            entries: HashMap::new(),
+            path: Some(path.to_path_buf()),
+        };
+
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return ban_list,
+            Err(err) => {
+                warn!("[BanList] Read ban list {:?} failed : {:?}", path, err);
+                return ban_list;
+            }
+        };
+        let file: BanListFile = match toml::from_str(&content) {
+            Ok(file) => file,
+            Err(err) => {
+                warn!("[BanList] Parse ban list {:?} failed : {}", path, err);
+                return ban_list;
+            }
+        };
+
+        for record in file.bans {
+            match BanTarget::from_str(&record.target) {
+                Ok(target) => {
+                    let entry = BanEntry {
+                        expires_at: record.expires_at.map(from_unix_secs),
+                        reason: record.reason,
+                    };
+                    ban_list.entries.insert(target, entry);
+                }
+                Err(err) => warn!("[BanList] Ignore ban record: {}", err),
+            }
+        }
+        ban_list.remove_expired();
+        info!(
+            "[BanList] Load {} bans from {:?}",
+            ban_list.entries.len(),
+            path
+        );
+
+        ban_list
+    }
+
+    /// Ban the target for `duration`, or forever if it is None.
+    pub fn ban(&mut self, target: BanTarget, duration: Option<Duration>, reason: String) {
+        let target = target.normalized();
+        let expires_at = duration.map(|duration| SystemTime::now() + duration);
+        info!(
+            "[BanList] Ban {} until {:?}, reason: {}",
+            target, expires_at, reason
+        );
+        self.entries.insert(target, BanEntry { expires_at, reason });
+        self.save();
+    }
+
+    pub fn unban(&mut self, target: &BanTarget) -> bool {
+        let target = &target.normalized();
+        let removed = self.entries.remove(target).is_some();
+        if removed {
+            info!("[BanList] Unban {}", target);
+            self.save();
+        }
+        removed
+    }
+
+    pub fn is_ip_banned(&self, ip: &IpAddr) -> bool {
+        let now = SystemTime::now();
+        self.entries
+            .iter()
+            .any(|(target, entry)| !entry.is_expired(now) && target.contains(ip))
+    }
+
+    pub fn is_key_banned(&self, key: &str) -> bool {
+        let now = SystemTime::now();
+        self.entries
+            .get(&BanTarget::PublicKey(normalize_key(key)))
+            .map(|entry| !entry.is_expired(now))
+            .unwrap_or(false)
+    }
+
+    pub fn entries(&self) -> &HashMap<BanTarget, BanEntry> {
+        &self.entries
+    }
+
+    /// Remove the expired bans, and save the list if anything is removed.
+    pub fn remove_expired(&mut self) {
+        let now = SystemTime::now();
+        let len = self.entries.len();
+        self.entries.retain(|_, entry| !entry.is_expired(now));
+        if self.entries.len() != len {
+            self.save();
+        }
+    }
+
+    fn save(&self) {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return,
+        };
+
+        let bans = self
+            .entries
+            .iter()
+            .map(|(target, entry)| BanRecord {
+                target: target.to_string(),
+                expires_at: entry.expires_at.map(to_unix_secs),
+                reason: entry.reason.clone(),
+            })
+            .collect();
+
+        if let Err(err) = write_toml_file(path, &BanListFile { bans }) {
+            warn!("[BanList] Save ban list {:?} failed : {:?}", path, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BanList, BanTarget};
+    use std::net::IpAddr;
+    use std::str::FromStr;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    fn ip(ip: &str) -> IpAddr {
+        IpAddr::from_str(ip).unwrap()
+    }
+
+    #[test]
+    fn parse_targets() {
+        assert_eq!(
+            BanTarget::from_str("ip:1.2.3.4"),
+            Ok(BanTarget::Ip(ip("1.2.3.4")))
+        );
+        assert_eq!(
+            BanTarget::from_str("subnet:10.0.0.0/8"),
+            Ok(BanTarget::Subnet(ip("10.0.0.0"), 8))
+        );
+        assert_eq!(
+            BanTarget::from_str("key:02AB"),
+            Ok(BanTarget::PublicKey("02ab".to_owned()))
+        );
+        assert!(BanTarget::from_str("subnet:10.0.0.0/33").is_err());
+        assert!(BanTarget::from_str("ip:1.2.3").is_err());
+        assert_eq!(
+            BanTarget::from_str("key:0x02AB"),
+            Ok(BanTarget::PublicKey("02ab".to_owned()))
+        );
+        assert!(BanTarget::from_str("key:").is_err());
+        assert!(BanTarget::from_str("key:0x").is_err());
+        assert!(BanTarget::from_str("1.2.3.4").is_err());
+
+        for target in &["ip:::1", "subnet:fe80::/10", "key:02ab"] {
+            assert_eq!(&BanTarget::from_str(target).unwrap().to_string(), target);
+        }
+    }
+
+    #[test]
+    fn ban_ip_subnet_and_key() {
+        let mut ban_list = BanList::default();
+        ban_list.ban(BanTarget::Ip(ip("1.2.3.4")), None, "test".to_owned());
+        ban_list.ban(
+            BanTarget::Subnet(ip("10.1.0.0"), 16),
+            None,
+            "test".to_owned(),
+        );
+        ban_list.ban(BanTarget::Subnet(ip("fe80::"), 10), None, "test".to_owned());
+        ban_list.ban(
+            BanTarget::PublicKey("02ab".to_owned()),
+            None,
+            "test".to_owned(),
+        );
+
+        assert!(ban_list.is_ip_banned(&ip("1.2.3.4")));
+        assert!(!ban_list.is_ip_banned(&ip("1.2.3.5")));
+        assert!(ban_list.is_ip_banned(&ip("10.1.255.1")));
+        assert!(!ban_list.is_ip_banned(&ip("10.2.0.1")));
+        assert!(ban_list.is_ip_banned(&ip("fe80::1")));
+        assert!(!ban_list.is_ip_banned(&ip("::1")));
+        assert!(ban_list.is_key_banned("02AB"));
+        assert!(!ban_list.is_key_banned("02ac"));
+
+        // Keys match however they are written.
+        ban_list.ban(
+            BanTarget::PublicKey("0x03CD".to_owned()),
+            None,
+            "test".to_owned(),
+        );
+        assert!(ban_list.is_key_banned("03cd"));
+        assert!(ban_list.unban(&BanTarget::PublicKey("0X03cd".to_owned())));
+        assert!(!ban_list.is_key_banned("03cd"));
+
+        assert!(ban_list.unban(&BanTarget::Ip(ip("1.2.3.4"))));
+        assert!(!ban_list.is_ip_banned(&ip("1.2.3.4")));
+        assert!(!ban_list.unban(&BanTarget::Ip(ip("1.2.3.4"))));
+    }
+
+    #[test]
+    fn ban_expires() {
+        let mut ban_list = BanList::default();
+        ban_list.ban(
+            BanTarget::Ip(ip("1.2.3.4")),
+            Some(Duration::from_secs(0)),
+            "test".to_owned(),
+        );
+        ban_list.ban(
+            BanTarget::Ip(ip("1.2.3.5")),
+            Some(Duration::from_secs(3600)),
+            "test".to_owned(),
+        );
+        assert!(!ban_list.is_ip_banned(&ip("1.2.3.4")));
+        assert!(ban_list.is_ip_banned(&ip("1.2.3.5")));
+
+        ban_list.remove_expired();
+        assert_eq!(ban_list.entries().len(), 1);
+    }
+
+    #[test]
+    fn persist_ban_list() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("network").join("banned_peers.toml");
+
+        let mut ban_list = BanList::load(&path);
+        ban_list.ban(BanTarget::Ip(ip("1.2.3.4")), None, "spam".to_owned());
+        ban_list.ban(
+            BanTarget::Subnet(ip("10.1.0.0"), 16),
+            Some(Duration::from_secs(3600)),
+            "eclipse".to_owned(),
+        );
+        ban_list.ban(
+            BanTarget::PublicKey("02ab".to_owned()),
+            Some(Duration::from_secs(0)),
+            "expired".to_owned(),
+        );
+
+        let ban_list = BanList::load(&path);
+        assert_eq!(ban_list.entries().len(), 2);
+        assert!(ban_list.is_ip_banned(&ip("1.2.3.4")));
+        assert!(ban_list.is_ip_banned(&ip("10.1.0.1")));
+        assert!(!ban_list.is_key_banned("02ab"));
+        assert_eq!(
+            ban_list.entries()[&BanTarget::Ip(ip("1.2.3.4"))].reason,
+            "spam"
+        );
+    }
+}
@@ -20,16 +20,11 @@
 use byteorder::{ByteOrder, NetworkEndian};
 use bytes::BufMut;
 use bytes::BytesMut;
+use crc::crc32::{self, Hasher32};
 use log::{error, warn};
-use std::io;
+use std::error::Error;
+use std::fmt;
 use std::str;
-use tokio::codec::{Decoder, Encoder};
-
-pub type CitaRequest = (String, Vec<u8>);
-pub type CitaResponse = Option<(String, Vec<u8>)>;
-
-/// Our multiplexed line-based codec
-pub struct CitaCodec;
 
 /// Implementation of the multiplexed line-based protocol.
 ///
@@ -51,9 +46,119 @@ pub struct CitaCodec;
 /// | Message                | a serialize data         |
 /// +------------------------+--------------------------+
 ///
+/// # A versioned frame:
+///
+/// The legacy frame above has no room for a version, so a versioned frame starts
+/// with another symbol. Decoders accept both, the encoder writes the legacy frame
+/// unless a version is given, so old nodes can still read the frames during a
+/// rolling upgrade.
+///
+/// +------------------------+--------------------------+
+/// | Type                   | Content                  |
+/// +------------------------+--------------------------+
+/// | Symbol for Start       | \xCAFEBEEF               |
+/// | Version                | u8                       |
+/// | Flags                  | u16                      |
+/// | Length of Full Payload | u32                      |
+/// +------------------------+--------------------------+
+/// | Length of Key          | u8                       |
+/// | Key                    | bytes of a str           |
+/// +------------------------+--------------------------+
+/// | Message                | a serialize data         |
+/// +------------------------+--------------------------+
+/// | Checksum, if flagged   | u32                      |
+/// +------------------------+--------------------------+
+///
+/// The checksum is the CRC32C of the frame before it, from the symbol to the end of
+/// the message, and it is counted in the length of the full payload.
+///
 
 // Start of network messages.
 const NETMSG_START: u64 = 0xDEAD_BEEF_0000_0000;
+// Start of versioned network messages.
+const NETMSG_START_VERSIONED: u32 = 0xCAFE_BEEF;
+const LEGACY_HEADER_LEN: usize = 8;
+const VERSIONED_HEADER_LEN: usize = 4 + 1 + 2 + 4;
+const LEGACY_MAGIC: [u8; 4] = [0xDE, 0xAD, 0xBE, 0xEF];
+const VERSIONED_MAGIC: [u8; 4] = [0xCA, 0xFE, 0xBE, 0xEF];
+const CHECKSUM_LEN: usize = 4;
+
+/// Frames longer than this are refused, the same as the limit of the transport codec.
+pub const MAX_FRAME_LENGTH: usize = 8 * 1024 * 1024;
+
+/// The frame without version and flags.
+pub const FRAME_VERSION_LEGACY: u8 = 0;
+pub const FRAME_VERSION_1: u8 = 1;
+pub const FRAME_VERSION_LATEST: u8 = FRAME_VERSION_1;
+
+// Bits of the flags.
+/// Two bits of the codec the body is compressed with, 0 if it is not compressed.
+pub const FLAG_COMPRESSION_MASK: u16 = 0b0_0011;
+/// The frame ends with a checksum.
+pub const FLAG_CHECKSUM: u16 = 0b0_0100;
+/// Two bits reserved for a priority, they are carried but not used yet.
+pub const FLAG_PRIORITY_MASK: u16 = 0b1_1000;
+/// The body is a fragment of a longer message.
+pub const FLAG_FRAGMENT: u16 = 0b10_0000;
+
+/// A decoded frame, with the header fields.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NetworkMessage {
+    pub version: u8,
+    pub flags: u16,
+    pub key: String,
+    pub body: Vec<u8>,
+}
+
+/// Why a frame can not be decoded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The buffer does not start with a frame symbol.
+    BadMagic,
+    UnsupportedVersion(u8),
+    /// The length of the frame is over `MAX_FRAME_LENGTH`.
+    Oversize(usize),
+    EmptyKey,
+    BadUtf8,
+    /// The frame is shorter than its key or checksum.
+    Truncated,
+    /// The frame uses features this node does not support.
+    UnsupportedFlags(u16),
+    /// The compressed body can not be decompressed.
+    BadCompression,
+    /// The checksum does not match the frame.
+    BadChecksum,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::BadMagic => write!(f, "bad frame symbol"),
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "frame version {} is not supported", version)
+            }
+            DecodeError::Oversize(length) => write!(
+                f,
+                "frame length {} is over the limit {}",
+                length, MAX_FRAME_LENGTH
+            ),
+            DecodeError::EmptyKey => write!(f, "frame key is empty"),
+            DecodeError::BadUtf8 => write!(f, "frame key is not UTF-8"),
+            DecodeError::Truncated => write!(f, "frame is shorter than its key or checksum"),
+            DecodeError::UnsupportedFlags(flags) => {
+                write!(f, "frame flags {:#06x} are not supported", flags)
+            }
+            DecodeError::BadCompression => write!(f, "frame body can not be decompressed"),
+            DecodeError::BadChecksum => write!(f, "frame checksum does not match"),
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+pub fn is_supported_version(version: u8) -> bool {
+    version <= FRAME_VERSION_LATEST
+}
 
 fn opt_bytes_extend(buf: &mut BytesMut, data: &[u8]) {
     buf.reserve(data.len());
@@ -63,116 +168,214 @@ fn opt_bytes_extend(buf: &mut BytesMut, data: &[u8]) {
     }
 }
 
-impl Decoder for CitaCodec {
-    type Item = CitaRequest;
-    type Error = io::Error;
-
-    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, io::Error> {
-        Ok(network_message_to_pubsub_message(buf))
-    }
+/// Encode the message in the legacy frame.
+pub fn pubsub_message_to_network_message(buf: &mut BytesMut, msg: Option<(String, Vec<u8>)>) {
+    encode_network_message(buf, FRAME_VERSION_LEGACY, 0, msg);
 }
 
-impl Encoder for CitaCodec {
-    type Item = CitaResponse;
-    type Error = io::Error;
-
-    fn encode(&mut self, msg: Self::Item, buf: &mut BytesMut) -> io::Result<()> {
-        pubsub_message_to_network_message(buf, msg);
-        Ok(())
+/// Encode the message in the frame of `version`. The legacy frame has no flags.
+pub fn encode_network_message(
+    buf: &mut BytesMut,
+    version: u8,
+    flags: u16,
+    msg: Option<(String, Vec<u8>)>,
+) {
+    if !is_supported_version(version) {
+        error!("The frame version {} is not supported.", version);
+        return;
+    }
+    if version == FRAME_VERSION_LEGACY && flags != 0 {
+        error!(
+            "The legacy frame has no flags, but {:#06x} is given.",
+            flags
+        );
+        return;
     }
-}
 
-pub fn pubsub_message_to_network_message(buf: &mut BytesMut, msg: Option<(String, Vec<u8>)>) {
-    let mut request_id_bytes = [0; 8];
-    if let Some((key, body)) = msg {
-        let length_key = key.len();
-        // Use 1 byte to store key length.
-        if length_key > u8::max_value() as usize {
-            error!("The MQ message key is too long {}.", key);
-            return;
-        }
-        // Use 1 bytes to store the length for key, then store key, the last part is body.
-        let length_full = 1 + length_key + body.len();
-        if length_full > u32::max_value() as usize {
-            error!(
-                "The MQ message with key {} is too long {}.",
-                key,
-                body.len()
-            );
-            return;
+    let length_checksum = if flags & FLAG_CHECKSUM != 0 {
+        CHECKSUM_LEN
+    } else {
+        0
+    };
+    let (length_full, payload) = match msg {
+        Some((key, body)) => {
+            let length_key = key.len();
+            // Use 1 byte to store key length.
+            if length_key > u8::max_value() as usize {
+                error!("The MQ message key is too long {}.", key);
+                return;
+            }
+            // Use 1 bytes to store the length for key, then store key, the last part is body.
+            let length_full = 1 + length_key + body.len() + length_checksum;
+            if length_full > u32::max_value() as usize {
+                error!(
+                    "The MQ message with key {} is too long {}.",
+                    key,
+                    body.len()
+                );
+                return;
+            }
+            (length_full, Some((key, body)))
         }
+        None => (length_checksum, None),
+    };
+
+    let start = buf.len();
+    if version == FRAME_VERSION_LEGACY {
+        let mut request_id_bytes = [0; 8];
         let request_id = NETMSG_START + length_full as u64;
         NetworkEndian::write_u64(&mut request_id_bytes, request_id);
         opt_bytes_extend(buf, &request_id_bytes);
-        buf.put_u8(length_key as u8);
+    } else {
+        buf.reserve(VERSIONED_HEADER_LEN);
+        buf.put_u32_be(NETMSG_START_VERSIONED);
+        buf.put_u8(version);
+        buf.put_u16_be(flags);
+        buf.put_u32_be(length_full as u32);
+    }
+    if let Some((key, body)) = payload {
+        buf.reserve(1);
+        buf.put_u8(key.len() as u8);
         opt_bytes_extend(buf, key.as_bytes());
         opt_bytes_extend(buf, &body);
-    } else {
-        let request_id = NETMSG_START;
-        NetworkEndian::write_u64(&mut request_id_bytes, request_id);
-        opt_bytes_extend(buf, &request_id_bytes);
+    }
+    if length_checksum != 0 {
+        let checksum = crc32::checksum_castagnoli(&buf[start..]);
+        buf.reserve(CHECKSUM_LEN);
+        buf.put_u32_be(checksum);
     }
 }
 
-pub fn network_message_to_pubsub_message(buf: &mut BytesMut) -> Option<(String, Vec<u8>)> {
-    if buf.len() < 8 {
-        return None;
+/// Decode the next message in the buffer. `Ok(None)` means more bytes are needed.
+/// Compressed and fragmented frames are refused, see `compression::decode_message`
+/// and `fragment::Reassembler`.
+pub fn network_message_to_pubsub_message(
+    buf: &mut BytesMut,
+) -> Result<Option<(String, Vec<u8>)>, DecodeError> {
+    match decode_network_message(buf)? {
+        Some(msg) => {
+            if msg.flags & (FLAG_COMPRESSION_MASK | FLAG_FRAGMENT) != 0 {
+                return Err(DecodeError::UnsupportedFlags(msg.flags));
+            }
+            Ok(Some((msg.key, msg.body)))
+        }
+        None => Ok(None),
     }
+}
 
-    let request_id = NetworkEndian::read_u64(buf.as_ref());
-    let netmsg_start = request_id & 0xffff_ffff_0000_0000;
-    let length_full = (request_id & 0x0000_0000_ffff_ffff) as usize;
-    if netmsg_start != NETMSG_START {
-        return None;
-    }
-    if length_full + 8 > buf.len() {
-        return None;
-    }
-    let _request_id_buf = buf.split_to(8);
+/// Decode the next frame in the buffer of either layout. The checksum is verified and
+/// removed, the other flags are left to the caller. `Ok(None)` means more bytes are
+/// needed. Empty frames are skipped.
+///
+/// Once the length of a frame is known, the frame is taken from the buffer even if
+/// it is malformed. On `BadMagic` and `Oversize` the bad frame is left in the buffer.
+pub fn decode_network_message(buf: &mut BytesMut) -> Result<Option<NetworkMessage>, DecodeError> {
+    loop {
+        if buf.len() < 4 {
+            return Ok(None);
+        }
 
-    if length_full == 0 {
-        return None;
+        let (version, flags, length_full, header_len) = if buf[..4] == VERSIONED_MAGIC {
+            if buf.len() < VERSIONED_HEADER_LEN {
+                return Ok(None);
+            }
+            let version = buf[4];
+            let flags = NetworkEndian::read_u16(&buf[5..7]);
+            let length_full = NetworkEndian::read_u32(&buf[7..11]) as usize;
+            (version, flags, length_full, VERSIONED_HEADER_LEN)
+        } else if buf[..4] == LEGACY_MAGIC {
+            if buf.len() < LEGACY_HEADER_LEN {
+                return Ok(None);
+            }
+            let request_id = NetworkEndian::read_u64(buf.as_ref());
+            let length_full = (request_id & 0x0000_0000_ffff_ffff) as usize;
+            (FRAME_VERSION_LEGACY, 0, length_full, LEGACY_HEADER_LEN)
+        } else {
+            return Err(DecodeError::BadMagic);
+        };
+
+        if length_full > MAX_FRAME_LENGTH {
+            return Err(DecodeError::Oversize(length_full));
+        }
+        if length_full + header_len > buf.len() {
+            return Ok(None);
+        }
+        let request_id_buf = buf.split_to(header_len);
+        let mut payload_buf = buf.split_to(length_full);
+
+        if header_len == VERSIONED_HEADER_LEN
+            && (version == FRAME_VERSION_LEGACY || !is_supported_version(version))
+        {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        if flags & FLAG_CHECKSUM != 0 {
+            if payload_buf.len() < CHECKSUM_LEN {
+                return Err(DecodeError::Truncated);
+            }
+            let checksum_buf = payload_buf.split_off(length_full - CHECKSUM_LEN);
+            let mut digest = crc32::Digest::new(crc32::CASTAGNOLI);
+            digest.write(&request_id_buf);
+            digest.write(&payload_buf);
+            if digest.sum32() != NetworkEndian::read_u32(&checksum_buf) {
+                return Err(DecodeError::BadChecksum);
+            }
+        }
+        if payload_buf.is_empty() {
+            continue;
+        }
+        return decode_payload(payload_buf).map(|(key, body)| {
+            Some(NetworkMessage {
+                version,
+                flags,
+                key,
+                body,
+            })
+        });
     }
-    let mut payload_buf = buf.split_to(length_full);
+}
 
+fn decode_payload(mut payload_buf: BytesMut) -> Result<(String, Vec<u8>), DecodeError> {
     let length_key = payload_buf[0] as usize;
     let _length_key_buf = payload_buf.split_to(1);
     if length_key == 0 {
-        error!("network message key is empty.");
-        return None;
+        return Err(DecodeError::EmptyKey);
     }
     if length_key > payload_buf.len() {
-        error!(
-            "Buffer is not enough for key {} > {}.",
-            length_key,
-            buf.len()
-        );
-        return None;
+        return Err(DecodeError::Truncated);
     }
     let key_buf = payload_buf.split_to(length_key);
-    let key_str_result = str::from_utf8(&key_buf);
-    if key_str_result.is_err() {
-        error!("network message parse key error {:?}.", key_buf);
-        return None;
-    }
-    let key = key_str_result.unwrap().to_string();
-    if length_full == 1 + length_key {
+    let key = str::from_utf8(&key_buf)
+        .map_err(|_| DecodeError::BadUtf8)?
+        .to_string();
+    if payload_buf.is_empty() {
         warn!("network message is empty.");
     }
-    Some((key, payload_buf.to_vec()))
+    Ok((key, payload_buf.to_vec()))
 }
 
 #[cfg(test)]
 mod test {
-    use super::{network_message_to_pubsub_message, pubsub_message_to_network_message};
+    use super::{
+        decode_network_message, encode_network_message, network_message_to_pubsub_message,
+        pubsub_message_to_network_message, DecodeError, FLAG_CHECKSUM, FLAG_PRIORITY_MASK,
+        FRAME_VERSION_1, FRAME_VERSION_LEGACY, MAX_FRAME_LENGTH,
+    };
+    use byteorder::{ByteOrder, NetworkEndian};
     use bytes::BytesMut;
 
+    fn frame(version: u8, key: &str, msg: &[u8]) -> BytesMut {
+        let mut buf = BytesMut::new();
+        encode_network_message(&mut buf, version, 0, Some((key.to_owned(), msg.to_vec())));
+        buf
+    }
+
     #[test]
     fn convert_empty_message() {
         let mut buf = BytesMut::with_capacity(4 + 4);
         pubsub_message_to_network_message(&mut buf, None);
         let pub_msg_opt = network_message_to_pubsub_message(&mut buf);
-        assert!(pub_msg_opt.is_none());
+        assert_eq!(pub_msg_opt, Ok(None));
+        assert!(buf.is_empty());
     }
 
     #[test]
@@ -181,10 +384,144 @@ mod test {
         let msg: Vec<u8> = vec![1, 3, 5, 7, 9];
         let mut buf = BytesMut::with_capacity(4 + 4 + 1 + key.len() + msg.len());
         pubsub_message_to_network_message(&mut buf, Some((key.clone(), msg.clone())));
-        let pub_msg_opt = network_message_to_pubsub_message(&mut buf);
+        let pub_msg_opt = network_message_to_pubsub_message(&mut buf).unwrap();
         assert!(pub_msg_opt.is_some());
         let (key_new, msg_new) = pub_msg_opt.unwrap();
         assert_eq!(key, key_new);
         assert_eq!(msg, msg_new);
     }
+
+    #[test]
+    fn convert_versioned_messages() {
+        let key = "this-is-the-key".to_string();
+        let msg: Vec<u8> = vec![1, 3, 5, 7, 9];
+        let mut buf = BytesMut::new();
+        encode_network_message(
+            &mut buf,
+            FRAME_VERSION_1,
+            FLAG_PRIORITY_MASK,
+            Some((key.clone(), msg.clone())),
+        );
+        assert_eq!(buf.len(), 11 + 1 + key.len() + msg.len());
+
+        let decoded = decode_network_message(&mut buf).unwrap().unwrap();
+        assert_eq!(decoded.version, FRAME_VERSION_1);
+        assert_eq!(decoded.flags, FLAG_PRIORITY_MASK);
+        assert_eq!(decoded.key, key);
+        assert_eq!(decoded.body, msg);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn decode_mixed_versions() {
+        let mut buf = frame(FRAME_VERSION_LEGACY, "legacy", &[1]);
+        // Empty frames are skipped.
+        pubsub_message_to_network_message(&mut buf, None);
+        buf.extend_from_slice(&frame(FRAME_VERSION_1, "versioned", &[2]));
+
+        let legacy = decode_network_message(&mut buf).unwrap().unwrap();
+        assert_eq!(legacy.version, FRAME_VERSION_LEGACY);
+        assert_eq!(legacy.key, "legacy");
+        let (key, msg) = network_message_to_pubsub_message(&mut buf)
+            .unwrap()
+            .unwrap();
+        assert_eq!(key, "versioned");
+        assert_eq!(msg, vec![2]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn reject_unsupported_frames() {
+        // Incomplete frame.
+        let mut buf = frame(FRAME_VERSION_1, "key", &[1]);
+        let len = buf.len();
+        let mut partial = BytesMut::from(&buf[..len - 1]);
+        assert_eq!(decode_network_message(&mut partial), Ok(None));
+        assert_eq!(partial.len(), len - 1);
+
+        // Unknown version, the frame is taken.
+        buf[4] = 9;
+        assert_eq!(
+            decode_network_message(&mut buf),
+            Err(DecodeError::UnsupportedVersion(9))
+        );
+        assert!(buf.is_empty());
+
+        // The legacy frame has no flags.
+        let mut buf = BytesMut::new();
+        encode_network_message(&mut buf, FRAME_VERSION_LEGACY, FLAG_CHECKSUM, None);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn checksum_frames() {
+        let mut buf = BytesMut::new();
+        encode_network_message(
+            &mut buf,
+            FRAME_VERSION_1,
+            FLAG_CHECKSUM,
+            Some(("key".to_owned(), vec![1, 2, 3])),
+        );
+        assert_eq!(buf.len(), 11 + 1 + 3 + 3 + 4);
+        // An empty frame with a checksum is skipped too.
+        encode_network_message(&mut buf, FRAME_VERSION_1, FLAG_CHECKSUM, None);
+        assert_eq!(
+            network_message_to_pubsub_message(&mut buf),
+            Ok(Some(("key".to_owned(), vec![1, 2, 3])))
+        );
+        assert_eq!(network_message_to_pubsub_message(&mut buf), Ok(None));
+        assert!(buf.is_empty());
+
+        // The header is covered by the checksum too.
+        let mut buf = BytesMut::new();
+        encode_network_message(
+            &mut buf,
+            FRAME_VERSION_1,
+            FLAG_CHECKSUM,
+            Some(("key".to_owned(), vec![1, 2, 3])),
+        );
+        buf[6] |= 0b1000;
+        assert_eq!(
+            decode_network_message(&mut buf),
+            Err(DecodeError::BadChecksum)
+        );
+        assert!(buf.is_empty());
+
+        // The frame is too short for a checksum.
+        let mut buf = frame(FRAME_VERSION_1, "k", &[1]);
+        buf[6] |= FLAG_CHECKSUM as u8;
+        assert_eq!(
+            decode_network_message(&mut buf),
+            Err(DecodeError::Truncated)
+        );
+    }
+
+    #[test]
+    fn decode_errors() {
+        let mut buf = BytesMut::from(&b"garbage!"[..]);
+        assert_eq!(decode_network_message(&mut buf), Err(DecodeError::BadMagic));
+
+        let mut buf = frame(FRAME_VERSION_LEGACY, "key", &[1]);
+        NetworkEndian::write_u32(&mut buf[4..8], MAX_FRAME_LENGTH as u32 + 1);
+        assert_eq!(
+            decode_network_message(&mut buf),
+            Err(DecodeError::Oversize(MAX_FRAME_LENGTH + 1))
+        );
+
+        let mut buf = frame(FRAME_VERSION_LEGACY, "key", &[1]);
+        buf[8] = 0;
+        assert_eq!(decode_network_message(&mut buf), Err(DecodeError::EmptyKey));
+
+        let mut buf = frame(FRAME_VERSION_LEGACY, "key", &[1]);
+        buf[8] = 10;
+        assert_eq!(
+            decode_network_message(&mut buf),
+            Err(DecodeError::Truncated)
+        );
+
+        let mut buf = frame(FRAME_VERSION_1, "key", &[1]);
+        buf[12] = 0xff;
+        assert_eq!(decode_network_message(&mut buf), Err(DecodeError::BadUtf8));
+        assert!(buf.is_empty());
+    }
 }
@@ -0,0 +1,394 @@
+use crate::citaprotocol::{
+    encode_network_message, DecodeError, NetworkMessage, FLAG_CHECKSUM, FLAG_COMPRESSION_MASK,
+    FRAME_VERSION_1,
+};
+use bytes::BytesMut;
+use log::warn;
+use std::io::{self, Read};
+
+/// Key of the frame which tells the peer the capabilities of this node, the body is
+/// the names separated by commas.
+pub const CAPABILITIES_KEY: &str = "network.capabilities";
+/// The capability to check the frame checksums.
+const CHECKSUM_CAPABILITY: &str = "crc32c";
+/// The capability to reassemble the fragmented messages.
+const FRAGMENT_CAPABILITY: &str = "fragment";
+
+/// Bodies shorter than this are sent uncompressed.
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// Codecs of the compressed bodies, in the order of preference.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Compression {
+    Lz4,
+    Snappy,
+    Zstd,
+}
+
+impl Compression {
+    pub fn all() -> Vec<Compression> {
+        vec![Compression::Lz4, Compression::Snappy, Compression::Zstd]
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::Lz4 => "lz4",
+            Compression::Snappy => "snappy",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Compression> {
+        match name {
+            "lz4" => Some(Compression::Lz4),
+            "snappy" => Some(Compression::Snappy),
+            "zstd" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    /// The codec bits in the frame flags.
+    pub fn flag(self) -> u16 {
+        match self {
+            Compression::Lz4 => 1,
+            Compression::Snappy => 2,
+            Compression::Zstd => 3,
+        }
+    }
+
+    /// The codec marked in the frame flags, `None` if the body is not compressed.
+    pub fn from_flags(flags: u16) -> Option<Compression> {
+        match flags & FLAG_COMPRESSION_MASK {
+            1 => Some(Compression::Lz4),
+            2 => Some(Compression::Snappy),
+            3 => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::Lz4 => lz4::block::compress(data, None, true),
+            Compression::Snappy => snap::raw::Encoder::new()
+                .compress_vec(data)
+                .map_err(Into::into),
+            Compression::Zstd => zstd::stream::encode_all(data, ZSTD_LEVEL),
+        }
+    }
+
+    /// Decompress the body, refuse it if it would be longer than `max_len`.
+    pub fn decompress(self, data: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+        let too_long = || io::Error::new(io::ErrorKind::InvalidData, "decompressed body too long");
+        match self {
+            Compression::Lz4 => {
+                // The length is prepended as an i32 in little endian.
+                if data.len() < 4 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                let length = u32::from(data[0])
+                    | u32::from(data[1]) << 8
+                    | u32::from(data[2]) << 16
+                    | u32::from(data[3]) << 24;
+                if length as usize > max_len {
+                    return Err(too_long());
+                }
+                lz4::block::decompress(data, None)
+            }
+            Compression::Snappy => {
+                if snap::raw::decompress_len(data)? > max_len {
+                    return Err(too_long());
+                }
+                snap::raw::Decoder::new()
+                    .decompress_vec(data)
+                    .map_err(Into::into)
+            }
+            Compression::Zstd => {
+                let mut body = Vec::new();
+                zstd::stream::Decoder::new(data)?
+                    .take(max_len as u64 + 1)
+                    .read_to_end(&mut body)?;
+                if body.len() > max_len {
+                    return Err(too_long());
+                }
+                Ok(body)
+            }
+        }
+    }
+}
+
+/// The codec to compress the bodies sent to a peer: the first one of ours the peer can
+/// decompress.
+pub fn negotiate(local: &[Compression], remote: &[Compression]) -> Option<Compression> {
+    local.iter().find(|codec| remote.contains(codec)).cloned()
+}
+
+/// What a node can read, told to the peers in the capabilities frame.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// The codecs it can decompress.
+    pub compression: Vec<Compression>,
+    /// It checks the frame checksums.
+    pub checksum: bool,
+    /// It reassembles the fragmented messages.
+    pub fragment: bool,
+}
+
+impl Capabilities {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut names: Vec<&str> = self.compression.iter().map(|codec| codec.name()).collect();
+        if self.checksum {
+            names.push(CHECKSUM_CAPABILITY);
+        }
+        if self.fragment {
+            names.push(FRAGMENT_CAPABILITY);
+        }
+        names.join(",").into_bytes()
+    }
+
+    /// Capabilities this node does not know are ignored.
+    pub fn decode(body: &[u8]) -> Self {
+        let body = String::from_utf8_lossy(body);
+        let names: Vec<&str> = body.split(',').map(str::trim).collect();
+        Capabilities {
+            compression: names
+                .iter()
+                .filter_map(|name| Compression::from_name(name))
+                .collect(),
+            checksum: names.contains(&CHECKSUM_CAPABILITY),
+            fragment: names.contains(&FRAGMENT_CAPABILITY),
+        }
+    }
+}
+
+/// Compress the body with the negotiated codec if it is at least `threshold` bytes
+/// long and gets shorter. Return the codec bits of the flags, and the body.
+pub fn compress_body(
+    compression: Option<Compression>,
+    threshold: usize,
+    body: Vec<u8>,
+) -> (u16, Vec<u8>) {
+    if let Some(codec) = compression {
+        if body.len() >= threshold {
+            match codec.compress(&body) {
+                Ok(compressed) => {
+                    if compressed.len() < body.len() {
+                        return (codec.flag(), compressed);
+                    }
+                }
+                Err(err) => warn!("Compress a message with {} failed: {}", codec.name(), err),
+            }
+        }
+    }
+    (0, body)
+}
+
+/// Encode the message to a peer. The body is compressed as `compress_body` does, and a
+/// checksum is added if `checksum` is set. The frame with flags is always versioned,
+/// the peer understands it as it told the capabilities. Otherwise the frame of
+/// `version` is used.
+pub fn encode_message(
+    buf: &mut BytesMut,
+    version: u8,
+    compression: Option<Compression>,
+    threshold: usize,
+    checksum: bool,
+    key: String,
+    body: Vec<u8>,
+) {
+    let (mut flags, body) = compress_body(compression, threshold, body);
+    if checksum {
+        flags |= FLAG_CHECKSUM;
+    }
+    let version = if flags != 0 {
+        FRAME_VERSION_1.max(version)
+    } else {
+        version
+    };
+    encode_network_message(buf, version, flags, Some((key, body)));
+}
+
+/// The key and the body of a decoded frame, decompressed if it is compressed. Bodies
+/// longer than `max_len` once decompressed are refused.
+pub fn decode_message(
+    msg: NetworkMessage,
+    max_len: usize,
+) -> Result<(String, Vec<u8>), DecodeError> {
+    match Compression::from_flags(msg.flags) {
+        Some(codec) => codec
+            .decompress(&msg.body, max_len)
+            .map(|body| (msg.key, body))
+            .map_err(|_| DecodeError::BadCompression),
+        None => Ok((msg.key, msg.body)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_message, encode_message, negotiate, Capabilities, Compression};
+    use crate::citaprotocol::{
+        decode_network_message, encode_network_message, network_message_to_pubsub_message,
+        DecodeError, FLAG_CHECKSUM, FRAME_VERSION_1, FRAME_VERSION_LEGACY, MAX_FRAME_LENGTH,
+    };
+    use bytes::BytesMut;
+
+    #[test]
+    fn negotiate_codec() {
+        let all = Compression::all();
+        let local = Capabilities {
+            compression: all.clone(),
+            checksum: true,
+            fragment: true,
+        };
+        assert_eq!(Capabilities::decode(&local.encode()), local);
+        assert_eq!(
+            Capabilities::decode(b"brotli, zstd,snappy"),
+            Capabilities {
+                compression: vec![Compression::Zstd, Compression::Snappy],
+                checksum: false,
+                fragment: false,
+            }
+        );
+        assert_eq!(Capabilities::decode(b""), Capabilities::default());
+
+        let remote = Capabilities::decode(b"zstd,snappy").compression;
+        assert_eq!(negotiate(&all, &remote), Some(Compression::Snappy));
+        assert_eq!(negotiate(&[Compression::Lz4], &remote), None);
+        assert_eq!(negotiate(&all, &[]), None);
+    }
+
+    #[test]
+    fn compress_messages() {
+        let body: Vec<u8> = (0..4096).map(|i| (i % 16) as u8).collect();
+        for codec in Compression::all() {
+            assert_eq!(Compression::from_flags(codec.flag()), Some(codec));
+
+            let mut buf = BytesMut::new();
+            encode_message(
+                &mut buf,
+                FRAME_VERSION_LEGACY,
+                Some(codec),
+                1024,
+                false,
+                "key".to_owned(),
+                body.clone(),
+            );
+            assert!(buf.len() < body.len());
+            let msg = decode_network_message(&mut buf).unwrap().unwrap();
+            assert_eq!(msg.version, FRAME_VERSION_1);
+            assert_eq!(Compression::from_flags(msg.flags), Some(codec));
+            assert_eq!(
+                decode_message(msg, MAX_FRAME_LENGTH),
+                Ok(("key".to_owned(), body.clone()))
+            );
+        }
+    }
+
+    #[test]
+    fn skip_compression() {
+        // Short bodies are not compressed, and the frame is readable by old nodes.
+        let mut buf = BytesMut::new();
+        encode_message(
+            &mut buf,
+            FRAME_VERSION_LEGACY,
+            Some(Compression::Zstd),
+            1024,
+            false,
+            "key".to_owned(),
+            vec![1; 100],
+        );
+        assert_eq!(
+            network_message_to_pubsub_message(&mut buf),
+            Ok(Some(("key".to_owned(), vec![1; 100])))
+        );
+
+        // So are the bodies which get no shorter.
+        let mut state = 0x2545_f491_u32;
+        let random: Vec<u8> = (0..2048)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        let mut buf = BytesMut::new();
+        encode_message(
+            &mut buf,
+            FRAME_VERSION_LEGACY,
+            Some(Compression::Lz4),
+            1024,
+            false,
+            "key".to_owned(),
+            random.clone(),
+        );
+        let msg = decode_network_message(&mut buf).unwrap().unwrap();
+        assert_eq!((msg.version, msg.flags), (FRAME_VERSION_LEGACY, 0));
+        assert_eq!(msg.body, random);
+    }
+
+    #[test]
+    fn checksum_messages() {
+        let body: Vec<u8> = (0..4096).map(|i| (i % 16) as u8).collect();
+        for compression in &[None, Some(Compression::Snappy)] {
+            let mut buf = BytesMut::new();
+            encode_message(
+                &mut buf,
+                FRAME_VERSION_LEGACY,
+                *compression,
+                1024,
+                true,
+                "key".to_owned(),
+                body.clone(),
+            );
+            let mut corrupted = buf.clone();
+            let last = corrupted.len() - 5;
+            corrupted[last] ^= 1;
+
+            let msg = decode_network_message(&mut buf).unwrap().unwrap();
+            assert_eq!(msg.version, FRAME_VERSION_1);
+            assert_eq!(msg.flags & FLAG_CHECKSUM, FLAG_CHECKSUM);
+            assert_eq!(Compression::from_flags(msg.flags), *compression);
+            assert_eq!(
+                decode_message(msg, MAX_FRAME_LENGTH),
+                Ok(("key".to_owned(), body.clone()))
+            );
+            assert_eq!(
+                decode_network_message(&mut corrupted),
+                Err(DecodeError::BadChecksum)
+            );
+            assert!(corrupted.is_empty());
+        }
+    }
+
+    #[test]
+    fn reject_bad_compression() {
+        let mut buf = BytesMut::new();
+        encode_network_message(
+            &mut buf,
+            FRAME_VERSION_1,
+            Compression::Snappy.flag(),
+            Some(("key".to_owned(), vec![0xff; 16])),
+        );
+        let msg = decode_network_message(&mut buf).unwrap().unwrap();
+        assert_eq!(
+            decode_message(msg, MAX_FRAME_LENGTH),
+            Err(DecodeError::BadCompression)
+        );
+
+        // A body which would decompress over the frame limit.
+        let bomb = Compression::Zstd
+            .compress(&vec![0; MAX_FRAME_LENGTH + 1])
+            .unwrap();
+        assert!(Compression::Zstd
+            .decompress(&bomb, MAX_FRAME_LENGTH)
+            .is_err());
+        let bomb = Compression::Lz4
+            .compress(&vec![0; MAX_FRAME_LENGTH + 1])
+            .unwrap();
+        assert!(Compression::Lz4
+            .decompress(&bomb, MAX_FRAME_LENGTH)
+            .is_err());
+    }
+}
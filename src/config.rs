@@ -1,32 +1,502 @@
+use crate::citaprotocol::{is_supported_version, FRAME_VERSION_LEGACY};
+use crate::compression::{Compression, DEFAULT_COMPRESSION_THRESHOLD};
+use crate::fragment::{
+    DEFAULT_FRAGMENT_SIZE, DEFAULT_MAX_MESSAGE_LENGTH, DEFAULT_MAX_REASSEMBLY_LENGTH,
+    DEFAULT_REASSEMBLY_TIMEOUT, MAX_FRAGMENT_SIZE,
+};
+use crate::node_manager::{DEFAULT_MAX_CONNECTS, DEFAULT_MAX_INBOUND, DEFAULT_PORT};
+use p2p::{multiaddr::Multiaddr, utils::multiaddr_to_socketaddr};
 use serde_derive::Deserialize;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
 use util::parse_config;
 
+pub const DEFAULT_DATA_DIR: &str = "data";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(err) => write!(f, "read config file error: {}", err),
+            ConfigError::Parse(err) => write!(f, "parse config file error: {}", err),
+        }
+    }
+}
+
+/// A problem found by `NetConfig::validate`, peers are located by their index in `[[peers]]`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationError {
+    InvalidPort { field: String, port: usize },
+    MissingPeerIp { index: usize },
+    MissingPeerPort { index: usize },
+    InvalidPeerHost { index: usize, host: String },
+    DuplicatePeer { index: usize, addr: SocketAddr },
+    DuplicatePeerHost { index: usize, host: String },
+    SelfAsPeer { index: usize, addr: SocketAddr },
+    InvalidListenAddr(String),
+    InvalidAdvertiseAddr(String),
+    UnsupportedFrameVersion(u8),
+    UnknownCompression(String),
+    InvalidFragmentSize(usize),
+    TlsNotSupported,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValidationError::InvalidPort { field, port } => {
+                write!(f, "{} = {} is not a valid port", field, port)
+            }
+            ValidationError::MissingPeerIp { index } => write!(f, "peers[{}].ip is missing", index),
+            ValidationError::MissingPeerPort { index } => {
+                write!(f, "peers[{}].port is missing", index)
+            }
+            ValidationError::InvalidPeerHost { index, host } => {
+                write!(f, "peers[{}].ip = {:?} is not a valid host", index, host)
+            }
+            ValidationError::DuplicatePeer { index, addr } => {
+                write!(f, "peers[{}] = {} is duplicated", index, addr)
+            }
+            ValidationError::DuplicatePeerHost { index, host } => {
+                write!(f, "peers[{}] = {} is duplicated", index, host)
+            }
+            ValidationError::SelfAsPeer { index, addr } => {
+                write!(f, "peers[{}] = {} is the address of this node", index, addr)
+            }
+            ValidationError::InvalidListenAddr(addr) => {
+                write!(f, "listen_addrs {:?} is not a valid multiaddr", addr)
+            }
+            ValidationError::InvalidAdvertiseAddr(addr) => {
+                write!(f, "advertise_addrs {:?} is not a valid multiaddr", addr)
+            }
+            ValidationError::UnsupportedFrameVersion(version) => {
+                write!(f, "frame_version = {} is not supported", version)
+            }
+            ValidationError::UnknownCompression(name) => write!(
+                f,
+                "compression {:?} is not one of \"lz4\", \"snappy\" and \"zstd\"",
+                name
+            ),
+            ValidationError::InvalidFragmentSize(size) => write!(
+                f,
+                "fragment_size = {} is not in 1..={}",
+                size, MAX_FRAGMENT_SIZE
+            ),
+            ValidationError::TlsNotSupported => {
+                write!(
+                    f,
+                    "enable_tls = true, but TLS is not supported in this build"
+                )
+            }
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct NetConfig {
     pub port: Option<usize>,
     pub peers: Option<Vec<PeerConfig>>,
+    /// Limit of the outbound sessions, replaced by `max_outbound` if it is set.
     pub max_connects: Option<usize>,
+    /// Limits of the sessions accepted from and dialed to other nodes.
+    pub max_inbound: Option<usize>,
+    pub max_outbound: Option<usize>,
+    /// Maximum number of addresses in the address book. Default to 1000.
+    pub max_known_addrs: Option<usize>,
+    /// Maximum number of new addresses accepted through discovery from the sessions on
+    /// an IP in an hour. Default to 100.
+    pub max_addrs_per_session: Option<usize>,
+    /// Maximum number of addresses in a /16 (IPv4) or /32 (IPv6) subnet selected to dial
+    /// or to share, loopback and private addresses are not limited. Default to 2.
+    pub max_addrs_per_bucket: Option<usize>,
+    /// Number of dials in flight at the same time. Default to 4.
+    pub max_dialing: Option<usize>,
+    /// A dial is failed if no session is opened in this number of seconds. Default to 10.
+    pub dial_timeout_secs: Option<u64>,
+    /// TLS is not supported in this build, the node refuses to start if it is true.
     pub enable_tls: Option<bool>,
+    /// Path of the node private key file, it will be generated if not exists.
+    pub private_key_path: Option<String>,
+    /// Multiaddrs to listen on, e.g. "/ip4/0.0.0.0/tcp/4000" or "/ip6/::/tcp/4000".
+    /// If not set, listen on "/ip4/127.0.0.1/tcp/{port}".
+    pub listen_addrs: Option<Vec<String>>,
+    /// Multiaddrs advertised to other nodes through discovery, used when the node is
+    /// behind NAT or port mapping. If not set, advertise the listen addresses.
+    pub advertise_addrs: Option<Vec<String>>,
+    /// Learn the external address from the addresses observed and reported by peers.
+    pub learn_external_addr: Option<bool>,
+    /// Hex encoded public keys of the nodes allowed to connect, if set with
+    /// `allowed_keys_file`, the nodes in both are allowed. Allow all nodes if neither is set.
+    pub allowed_keys: Option<Vec<String>>,
+    /// A file of allowed public keys, one per line, it is reloaded when changed.
+    pub allowed_keys_file: Option<String>,
+    /// Directory to save the network data, such as the ban list and the address book.
+    /// Default to "data".
+    pub data_dir: Option<String>,
+    /// Ban a node for `misbehave_ban_secs` seconds when its misbehaviour score reported
+    /// by discovery drops below this. Default to 40 and 3600.
+    pub misbehave_ban_score: Option<i32>,
+    pub misbehave_ban_secs: Option<u64>,
+    /// Keep the network to the configured nodes: the discovery protocol is off, and the
+    /// sessions are refused unless the address is in `[[peers]]` or the public key is
+    /// in the allowed keys. Default to false.
+    pub validator_only: Option<bool>,
+    /// Version of the frames sent to other nodes, 0 for the legacy frame. Frames of all
+    /// versions are accepted, so raise it only after every node is upgraded. Default to 0.
+    pub frame_version: Option<u8>,
+    /// Codecs to compress the transfer messages with, in the order of preference, out
+    /// of "lz4", "snappy" and "zstd". The codec is negotiated with each peer, and the
+    /// messages to old nodes are not compressed. An empty list turns the compression
+    /// off. Default to all of them.
+    pub compression: Option<Vec<String>>,
+    /// Messages shorter than this are sent uncompressed. Default to 1024 bytes.
+    pub compression_threshold: Option<usize>,
+    /// Add a CRC32C checksum to the frames sent to the peers which check it, frames
+    /// which fail the check are dropped. Default to false.
+    pub enable_checksum: Option<bool>,
+    /// Messages longer than this are split into fragments of this size, for the peers
+    /// which can reassemble them. Default to 256 KiB, at most 4 MiB.
+    pub fragment_size: Option<usize>,
+    /// Fragmented messages longer than this are refused, and the unfinished ones are
+    /// dropped after `reassembly_timeout_secs`. Default to 64 MiB and 60 seconds.
+    pub max_message_length: Option<usize>,
+    pub reassembly_timeout_secs: Option<u64>,
+    /// Memory taken by the unfinished messages of all the sessions, the fragments over
+    /// it are dropped. Default to 256 MiB.
+    pub max_reassembly_length: Option<usize>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct PeerConfig {
+    /// An IP address, or a hostname which is resolved periodically.
     pub ip: Option<String>,
     pub port: Option<usize>,
+    /// The common name in the peer certificate, to verify the peer when TLS is enabled.
+    pub common_name: Option<String>,
+    /// Reserved peers are always kept connected, such as the other validators. They are
+    /// dialed first, do not count toward the session limits, and are never evicted.
+    /// Default to false.
+    pub reserved: Option<bool>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PeerAddress {
+    Ip(SocketAddr),
+    Host(String, u16),
+}
+
+impl PeerConfig {
+    pub fn address(&self) -> Option<PeerAddress> {
+        match (&self.ip, self.port) {
+            (Some(ip), Some(port)) if is_valid_port(port) => match parse_ip(ip) {
+                Some(ip) => Some(PeerAddress::Ip(SocketAddr::new(ip, port as u16))),
+                None if is_valid_hostname(ip.trim()) => {
+                    Some(PeerAddress::Host(ip.trim().to_owned(), port as u16))
+                }
+                None => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn is_reserved(&self) -> bool {
+        self.reserved.unwrap_or(false)
+    }
+
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        match self.address() {
+            Some(PeerAddress::Ip(addr)) => Some(addr),
+            _ => None,
+        }
+    }
+}
+
+// Accept both "::1" and "[::1]" for IPv6.
+fn parse_ip(ip: &str) -> Option<IpAddr> {
+    let ip = ip.trim();
+    let ip = if ip.starts_with('[') && ip.ends_with(']') {
+        &ip[1..ip.len() - 1]
+    } else {
+        ip
+    };
+    IpAddr::from_str(ip).ok()
+}
+
+// A RFC 1123 hostname, e.g. "node-0.cita.svc.cluster.local".
+fn is_valid_hostname(host: &str) -> bool {
+    let host = if host.ends_with('.') {
+        &host[..host.len() - 1]
+    } else {
+        host
+    };
+    !host.is_empty()
+        && host.len() <= 253
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+fn is_valid_port(port: usize) -> bool {
+    port > 0 && port <= usize::from(u16::max_value())
 }
 
 impl NetConfig {
     pub fn new(path: &str) -> Self {
         parse_config!(NetConfig, path)
     }
+
+    // Unlike `new`, return an error instead of panic, used for reloading a running node.
+    pub fn load(path: &str) -> Result<Self, ConfigError> {
+        let mut buffer = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut buffer))
+            .map_err(ConfigError::Io)?;
+        toml::from_str(&buffer).map_err(ConfigError::Parse)
+    }
+
+    /// Check the whole config, and return all the problems found instead of the first one.
+    pub fn validate(&self) -> Result<(), Vec<ValidationError>> {
+        let mut errors = Vec::new();
+
+        if let Some(port) = self.port {
+            if !is_valid_port(port) {
+                errors.push(ValidationError::InvalidPort {
+                    field: "port".to_owned(),
+                    port,
+                });
+            }
+        }
+
+        if let Some(version) = self.frame_version {
+            if !is_supported_version(version) {
+                errors.push(ValidationError::UnsupportedFrameVersion(version));
+            }
+        }
+
+        for name in self.compression.iter().flatten() {
+            if Compression::from_name(name).is_none() {
+                errors.push(ValidationError::UnknownCompression(name.clone()));
+            }
+        }
+
+        // Never run in plaintext while the operator believes TLS is on.
+        if self.enable_tls.unwrap_or(false) {
+            errors.push(ValidationError::TlsNotSupported);
+        }
+
+        if let Some(size) = self.fragment_size {
+            if size == 0 || size > MAX_FRAGMENT_SIZE {
+                errors.push(ValidationError::InvalidFragmentSize(size));
+            }
+        }
+
+        let mut self_addrs = HashSet::new();
+        for addr in self.listen_addrs() {
+            match addr.parse::<Multiaddr>() {
+                Ok(multiaddr) => {
+                    if let Some(socket_addr) = multiaddr_to_socketaddr(&multiaddr) {
+                        self_addrs.insert(socket_addr);
+                    }
+                }
+                Err(_) => errors.push(ValidationError::InvalidListenAddr(addr)),
+            }
+        }
+        if let Some(ref addrs) = self.advertise_addrs {
+            for addr in addrs {
+                match addr.parse::<Multiaddr>() {
+                    Ok(multiaddr) => {
+                        if let Some(socket_addr) = multiaddr_to_socketaddr(&multiaddr) {
+                            self_addrs.insert(socket_addr);
+                        }
+                    }
+                    Err(_) => errors.push(ValidationError::InvalidAdvertiseAddr(addr.clone())),
+                }
+            }
+        }
+        let is_self = |addr: &SocketAddr| {
+            self_addrs.contains(addr)
+                || self_addrs.iter().any(|self_addr| {
+                    self_addr.port() == addr.port()
+                        && (self_addr.ip().is_unspecified() || self_addr.ip().is_loopback())
+                        && (addr.ip().is_unspecified() || addr.ip().is_loopback())
+                })
+        };
+
+        let mut peer_addrs = HashSet::new();
+        let mut peer_hosts = HashSet::new();
+        for (index, peer) in self.peers.iter().flatten().enumerate() {
+            let ip = match peer.ip {
+                Some(ref ip) => Some(ip),
+                None => {
+                    errors.push(ValidationError::MissingPeerIp { index });
+                    None
+                }
+            };
+            let port = match peer.port {
+                Some(port) if is_valid_port(port) => Some(port as u16),
+                Some(port) => {
+                    errors.push(ValidationError::InvalidPort {
+                        field: format!("peers[{}].port", index),
+                        port,
+                    });
+                    None
+                }
+                None => {
+                    errors.push(ValidationError::MissingPeerPort { index });
+                    None
+                }
+            };
+            let host = ip.map(|ip| ip.trim());
+            let ip = match host {
+                Some(host) => match parse_ip(host) {
+                    Some(ip) => Some(ip),
+                    None if is_valid_hostname(host) => {
+                        if let Some(port) = port {
+                            if !peer_hosts.insert((host.to_owned(), port)) {
+                                errors.push(ValidationError::DuplicatePeerHost {
+                                    index,
+                                    host: format!("{}:{}", host, port),
+                                });
+                            }
+                        }
+                        None
+                    }
+                    None => {
+                        errors.push(ValidationError::InvalidPeerHost {
+                            index,
+                            host: host.to_owned(),
+                        });
+                        None
+                    }
+                },
+                None => None,
+            };
+
+            if let (Some(ip), Some(port)) = (ip, port) {
+                let addr = SocketAddr::new(ip, port);
+                if is_self(&addr) {
+                    errors.push(ValidationError::SelfAsPeer { index, addr });
+                } else if !peer_addrs.insert(addr) {
+                    errors.push(ValidationError::DuplicatePeer { index, addr });
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn data_dir(&self) -> PathBuf {
+        PathBuf::from(
+            self.data_dir
+                .as_ref()
+                .map(String::as_str)
+                .unwrap_or(DEFAULT_DATA_DIR),
+        )
+    }
+
+    pub fn max_inbound(&self) -> usize {
+        self.max_inbound.unwrap_or(DEFAULT_MAX_INBOUND)
+    }
+
+    pub fn max_outbound(&self) -> usize {
+        self.max_outbound
+            .or(self.max_connects)
+            .unwrap_or(DEFAULT_MAX_CONNECTS)
+    }
+
+    pub fn learn_external_addr(&self) -> bool {
+        self.learn_external_addr.unwrap_or(false)
+    }
+
+    pub fn is_validator_only(&self) -> bool {
+        self.validator_only.unwrap_or(false)
+    }
+
+    pub fn frame_version(&self) -> u8 {
+        self.frame_version.unwrap_or(FRAME_VERSION_LEGACY)
+    }
+
+    pub fn compression(&self) -> Vec<Compression> {
+        match self.compression {
+            Some(ref names) => names
+                .iter()
+                .filter_map(|name| Compression::from_name(name))
+                .collect(),
+            None => Compression::all(),
+        }
+    }
+
+    pub fn fragment_size(&self) -> usize {
+        self.fragment_size.unwrap_or(DEFAULT_FRAGMENT_SIZE)
+    }
+
+    pub fn max_message_length(&self) -> usize {
+        self.max_message_length
+            .unwrap_or(DEFAULT_MAX_MESSAGE_LENGTH)
+    }
+
+    pub fn max_reassembly_length(&self) -> usize {
+        self.max_reassembly_length
+            .unwrap_or(DEFAULT_MAX_REASSEMBLY_LENGTH)
+    }
+
+    pub fn reassembly_timeout(&self) -> Duration {
+        self.reassembly_timeout_secs
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_REASSEMBLY_TIMEOUT)
+    }
+
+    pub fn compression_threshold(&self) -> usize {
+        self.compression_threshold
+            .unwrap_or(DEFAULT_COMPRESSION_THRESHOLD)
+    }
+
+    pub fn listen_addrs(&self) -> Vec<String> {
+        match self.listen_addrs {
+            Some(ref addrs) => addrs.clone(),
+            None => vec![format!(
+                "/ip4/127.0.0.1/tcp/{}",
+                self.port.unwrap_or(DEFAULT_PORT)
+            )],
+        }
+    }
 }
 
 #[cfg(test)]
-mod tests {
-    use super::NetConfig;
+pub mod tests {
+    use super::{ConfigError, NetConfig, PeerAddress, ValidationError};
+    use crate::compression::Compression;
     use std::io::Write;
+    use std::time::Duration;
     use tempfile::NamedTempFile;
 
+    // Also used by the tests of the other modules.
+    pub fn config_from_str(toml_str: &str) -> NetConfig {
+        let mut tmp_file: NamedTempFile = NamedTempFile::new().unwrap();
+        tmp_file.write_all(toml_str.as_bytes()).unwrap();
+        let path = tmp_file.path().to_str().unwrap();
+        NetConfig::new(path)
+    }
+
     #[test]
     fn basic_test() {
         let toml_str = r#"
@@ -34,10 +504,12 @@ mod tests {
         enable_tls = true
         max_connects = 4
         id_card = 9
+        private_key_path = "node.key"
         [[peers]]
             ip = "0.0.0.0"
             port = 4001
             common_name = "test1.cita"
+            reserved = true
         [[peers]]
             ip = "0.0.0.0"
             port = 4002
@@ -51,6 +523,274 @@ mod tests {
         assert_eq!(config.port, Some(4000));
         assert_eq!(config.max_connects, Some(4));
         assert_eq!(config.enable_tls, Some(true));
-        assert_eq!(config.peers.unwrap().len(), 2);
+        assert_eq!(config.private_key_path, Some("node.key".to_owned()));
+        assert_eq!(
+            config.listen_addrs(),
+            vec!["/ip4/127.0.0.1/tcp/4000".to_owned()]
+        );
+        let peers = config.peers.unwrap();
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[0].common_name, Some("test1.cita".to_owned()));
+        assert_eq!(peers[1].common_name, None);
+        assert!(peers[0].is_reserved());
+        assert!(!peers[1].is_reserved());
+    }
+
+    #[test]
+    fn load_test() {
+        let mut tmp_file: NamedTempFile = NamedTempFile::new().unwrap();
+        tmp_file.write_all(b"max_connects = 8").unwrap();
+        let path = tmp_file.path().to_str().unwrap();
+        let config = NetConfig::load(path).unwrap();
+        assert_eq!(config.max_connects, Some(8));
+
+        let mut tmp_file: NamedTempFile = NamedTempFile::new().unwrap();
+        tmp_file.write_all(b"max_connects = \"eight\"").unwrap();
+        let path = tmp_file.path().to_str().unwrap();
+        match NetConfig::load(path) {
+            Err(ConfigError::Parse(_)) => {}
+            _ => panic!("expect a parse error"),
+        }
+
+        match NetConfig::load("/not/exist/network.toml") {
+            Err(ConfigError::Io(_)) => {}
+            _ => panic!("expect an io error"),
+        }
+    }
+
+    #[test]
+    fn listen_addrs_test() {
+        let toml_str = r#"
+        port = 4000
+        listen_addrs = ["/ip4/0.0.0.0/tcp/4000", "/ip6/::/tcp/4000", "/ip4/0.0.0.0/tcp/4100"]
+        advertise_addrs = ["/ip4/1.2.3.4/tcp/14000"]
+        learn_external_addr = true
+        "#;
+
+        let mut tmp_file: NamedTempFile = NamedTempFile::new().unwrap();
+        tmp_file.write_all(toml_str.as_bytes()).unwrap();
+        let path = tmp_file.path().to_str().unwrap();
+        let config = NetConfig::new(path);
+
+        assert_eq!(
+            config.listen_addrs(),
+            vec![
+                "/ip4/0.0.0.0/tcp/4000".to_owned(),
+                "/ip6/::/tcp/4000".to_owned(),
+                "/ip4/0.0.0.0/tcp/4100".to_owned(),
+            ]
+        );
+        assert_eq!(
+            config.advertise_addrs,
+            Some(vec!["/ip4/1.2.3.4/tcp/14000".to_owned()])
+        );
+        assert_eq!(config.learn_external_addr, Some(true));
+        assert_eq!(config.allowed_keys, None);
+    }
+
+    #[test]
+    fn session_limits_test() {
+        let config = config_from_str("port = 4000");
+        assert_eq!(config.max_inbound(), 8);
+        assert_eq!(config.max_outbound(), 4);
+
+        let config = config_from_str("max_connects = 6");
+        assert_eq!(config.max_outbound(), 6);
+
+        let config = config_from_str(
+            r#"
+            max_connects = 6
+            max_inbound = 20
+            max_outbound = 10
+            "#,
+        );
+        assert_eq!(config.max_inbound(), 20);
+        assert_eq!(config.max_outbound(), 10);
+    }
+
+    #[test]
+    fn validate_ok() {
+        let config = config_from_str(
+            r#"
+            port = 4000
+            [[peers]]
+                ip = "127.0.0.1"
+                port = 4001
+            [[peers]]
+                ip = "::1"
+                port = 4001
+            [[peers]]
+                ip = "[fe80::1]"
+                port = 4002
+            [[peers]]
+                ip = "node-1.cita.svc.cluster.local"
+                port = 4000
+            "#,
+        );
+        assert_eq!(config.validate(), Ok(()));
+        let addrs: Vec<PeerAddress> = config
+            .peers
+            .unwrap()
+            .iter()
+            .map(|peer| peer.address().unwrap())
+            .collect();
+        assert_eq!(
+            addrs,
+            vec![
+                PeerAddress::Ip("127.0.0.1:4001".parse().unwrap()),
+                PeerAddress::Ip("[::1]:4001".parse().unwrap()),
+                PeerAddress::Ip("[fe80::1]:4002".parse().unwrap()),
+                PeerAddress::Host("node-1.cita.svc.cluster.local".to_owned(), 4000),
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_without_peers() {
+        let config = config_from_str("port = 4000");
+        assert!(config.peers.is_none());
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn validate_reports_all_errors() {
+        let config = config_from_str(
+            r#"
+            port = 70000
+            listen_addrs = ["/ip4/127.0.0.1/tcp/4000", "not-a-multiaddr"]
+            [[peers]]
+                ip = "127.0.0.1"
+                port = 4001
+            [[peers]]
+                ip = "127.0.0.1"
+                port = 4001
+            [[peers]]
+                ip = "127.0.0.1"
+                port = 4000
+            [[peers]]
+                ip = "not..a..host"
+                port = 4003
+            [[peers]]
+                ip = "127.0.0.1"
+                port = 0
+            [[peers]]
+                port = 4005
+            [[peers]]
+            [[peers]]
+                ip = "node-1.cita"
+                port = 4007
+            [[peers]]
+                ip = "node-1.cita"
+                port = 4007
+            "#,
+        );
+        assert_eq!(
+            config.validate(),
+            Err(vec![
+                ValidationError::InvalidPort {
+                    field: "port".to_owned(),
+                    port: 70000,
+                },
+                ValidationError::InvalidListenAddr("not-a-multiaddr".to_owned()),
+                ValidationError::DuplicatePeer {
+                    index: 1,
+                    addr: "127.0.0.1:4001".parse().unwrap(),
+                },
+                ValidationError::SelfAsPeer {
+                    index: 2,
+                    addr: "127.0.0.1:4000".parse().unwrap(),
+                },
+                ValidationError::InvalidPeerHost {
+                    index: 3,
+                    host: "not..a..host".to_owned(),
+                },
+                ValidationError::InvalidPort {
+                    field: "peers[4].port".to_owned(),
+                    port: 0,
+                },
+                ValidationError::MissingPeerIp { index: 5 },
+                ValidationError::MissingPeerIp { index: 6 },
+                ValidationError::MissingPeerPort { index: 6 },
+                ValidationError::DuplicatePeerHost {
+                    index: 8,
+                    host: "node-1.cita:4007".to_owned(),
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn validate_frame_version() {
+        let config = config_from_str("port = 4000");
+        assert_eq!(config.frame_version(), 0);
+
+        let config = config_from_str("port = 4000\nframe_version = 1");
+        assert_eq!(config.validate(), Ok(()));
+        assert_eq!(config.frame_version(), 1);
+
+        let config = config_from_str("port = 4000\nframe_version = 2");
+        assert_eq!(
+            config.validate(),
+            Err(vec![ValidationError::UnsupportedFrameVersion(2)])
+        );
+    }
+
+    #[test]
+    fn validate_compression() {
+        let config = config_from_str("port = 4000");
+        assert_eq!(config.compression(), Compression::all());
+        assert_eq!(config.compression_threshold(), 1024);
+
+        let config = config_from_str("port = 4000\ncompression = []");
+        assert_eq!(config.validate(), Ok(()));
+        assert!(config.compression().is_empty());
+
+        let config = config_from_str("port = 4000\ncompression = [\"zstd\", \"gzip\"]");
+        assert_eq!(
+            config.validate(),
+            Err(vec![ValidationError::UnknownCompression("gzip".to_owned())])
+        );
+        assert_eq!(config.compression(), vec![Compression::Zstd]);
+    }
+
+    #[test]
+    fn validate_fragment_size() {
+        let config = config_from_str("port = 4000");
+        assert_eq!(config.fragment_size(), 256 * 1024);
+        assert_eq!(config.max_message_length(), 64 * 1024 * 1024);
+        assert_eq!(config.max_reassembly_length(), 256 * 1024 * 1024);
+        assert_eq!(config.reassembly_timeout(), Duration::from_secs(60));
+
+        let config = config_from_str("port = 4000\nfragment_size = 0");
+        assert_eq!(
+            config.validate(),
+            Err(vec![ValidationError::InvalidFragmentSize(0)])
+        );
+        let config = config_from_str("port = 4000\nfragment_size = 8388608");
+        assert_eq!(
+            config.validate(),
+            Err(vec![ValidationError::InvalidFragmentSize(8 * 1024 * 1024)])
+        );
+    }
+
+    #[test]
+    fn validate_tls() {
+        let config = config_from_str("port = 4000\nenable_tls = false");
+        assert_eq!(config.validate(), Ok(()));
+
+        let config = config_from_str(
+            r#"
+            port = 4000
+            enable_tls = true
+            [[peers]]
+                ip = "127.0.0.1"
+                port = 4001
+                common_name = "node1.cita"
+            "#,
+        );
+        assert_eq!(
+            config.validate(),
+            Err(vec![ValidationError::TlsNotSupported])
+        );
     }
 }
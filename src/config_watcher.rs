@@ -0,0 +1,189 @@
+use crate::config::{NetConfig, PeerAddress};
+use crate::node_manager::{
+    AddNodeReq, AddPeerHostReq, DelNodeReq, DelPeerHostReq, NodesManagerClient, SetAllowedKeysReq,
+    SetMaxConnectsReq,
+};
+use crossbeam_channel;
+use crossbeam_channel::{select, tick};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+pub const CHECK_CONFIG_FILE: Duration = Duration::from_secs(5);
+pub const CHECK_RELOAD_SIGNAL: Duration = Duration::from_secs(1);
+
+/// Watch the network config file, and apply the changes of `[[peers]]`,
+/// the session limits and `allowed_keys` to the running nodes manager.
+///
+/// The file is reloaded when its modified time changes, or on `SIGHUP`.
+pub struct ConfigWatcher {
+    path: String,
+    config: NetConfig,
+    modified: Option<SystemTime>,
+    nodes_mgr_client: NodesManagerClient,
+    sighup: Arc<AtomicBool>,
+    check_config_file: crossbeam_channel::Receiver<Instant>,
+    check_reload_signal: crossbeam_channel::Receiver<Instant>,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: &str, config: NetConfig, nodes_mgr_client: NodesManagerClient) -> Self {
+        let sighup = Arc::new(AtomicBool::new(false));
+        if let Err(err) = signal_hook::flag::register(signal_hook::SIGHUP, Arc::clone(&sighup)) {
+            warn!(
+                "[ConfigWatcher] Register SIGHUP failed, only watch the modified time: {:?}",
+                err
+            );
+        }
+
+        ConfigWatcher {
+            path: path.to_owned(),
+            config,
+            modified: modified_time(path),
+            nodes_mgr_client,
+            sighup,
+            check_config_file: tick(CHECK_CONFIG_FILE),
+            check_reload_signal: tick(CHECK_RELOAD_SIGNAL),
+        }
+    }
+
+    pub fn run(&mut self) {
+        loop {
+            select! {
+                recv(self.check_config_file) -> _ => {
+                    self.check();
+                }
+                recv(self.check_reload_signal) -> _ => {
+                    if self.sighup.swap(false, Ordering::SeqCst) {
+                        info!("[ConfigWatcher] Receive SIGHUP");
+                        self.reload();
+                    }
+                }
+            }
+        }
+    }
+
+    fn check(&mut self) {
+        let modified = modified_time(&self.path);
+        if modified.is_none() || modified == self.modified {
+            return;
+        }
+        self.reload();
+    }
+
+    /// Load the config file again, and apply it if it is valid.
+    pub fn reload(&mut self) {
+        self.modified = modified_time(&self.path);
+
+        match NetConfig::load(&self.path) {
+            Ok(config) => match config.validate() {
+                Ok(()) => {
+                    info!("[ConfigWatcher] Reload config file {}", self.path);
+                    self.apply(config);
+                }
+                Err(errors) => {
+                    for err in errors {
+                        warn!("[ConfigWatcher] Invalid network config: {}", err);
+                    }
+                    warn!(
+                        "[ConfigWatcher] Config file {} is invalid, keep the running config",
+                        self.path
+                    );
+                }
+            },
+            Err(err) => {
+                warn!(
+                    "[ConfigWatcher] Reload config file {} failed, keep the running config: {}",
+                    self.path, err
+                );
+            }
+        }
+    }
+
+    fn apply(&mut self, config: NetConfig) {
+        let old_peers = peer_addrs(&self.config);
+        let new_peers = peer_addrs(&config);
+
+        // Adding a peer again updates its reserved flag.
+        for (addr, reserved) in &new_peers {
+            if old_peers.get(addr) == Some(reserved) {
+                continue;
+            }
+            info!(
+                "[ConfigWatcher] Add peer {:?}, reserved: {}",
+                addr, reserved
+            );
+            match addr {
+                PeerAddress::Ip(addr) => {
+                    let req = if *reserved {
+                        AddNodeReq::new_reserved(*addr)
+                    } else {
+                        AddNodeReq::new_persistent(*addr)
+                    };
+                    self.nodes_mgr_client.add_node(req);
+                }
+                PeerAddress::Host(host, port) => self
+                    .nodes_mgr_client
+                    .add_peer_host(AddPeerHostReq::new(host.clone(), *port, *reserved)),
+            }
+        }
+        for addr in old_peers
+            .keys()
+            .filter(|addr| !new_peers.contains_key(addr))
+        {
+            info!("[ConfigWatcher] Remove peer {:?}", addr);
+            match addr {
+                PeerAddress::Ip(addr) => self.nodes_mgr_client.del_node(DelNodeReq::new(*addr)),
+                PeerAddress::Host(host, port) => self
+                    .nodes_mgr_client
+                    .del_peer_host(DelPeerHostReq::new(host.clone(), *port)),
+            }
+        }
+
+        let old_limits = (self.config.max_inbound(), self.config.max_outbound());
+        let new_limits = (config.max_inbound(), config.max_outbound());
+        if old_limits != new_limits {
+            info!(
+                "[ConfigWatcher] Change (max_inbound, max_outbound) from {:?} to {:?}",
+                old_limits, new_limits
+            );
+            self.nodes_mgr_client
+                .set_max_connects(SetMaxConnectsReq::new(new_limits.0, new_limits.1));
+        }
+
+        if self.config.allowed_keys != config.allowed_keys {
+            info!("[ConfigWatcher] Change allowed_keys");
+            self.nodes_mgr_client
+                .set_allowed_keys(SetAllowedKeysReq::new(
+                    config.allowed_keys.clone().unwrap_or_default(),
+                ));
+        }
+
+        self.config = config;
+    }
+}
+
+fn modified_time(path: &str) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+// The configured peers, and whether they are reserved.
+fn peer_addrs(config: &NetConfig) -> HashMap<PeerAddress, bool> {
+    let mut addrs = HashMap::new();
+    if let Some(ref peers) = config.peers {
+        for peer in peers {
+            match peer.address() {
+                Some(addr) => {
+                    addrs.insert(addr, peer.is_reserved());
+                }
+                None => warn!("[ConfigWatcher] Ignore invalid peer {:?}", peer),
+            }
+        }
+    }
+    addrs
+}
@@ -0,0 +1,151 @@
+use crate::peer_score::dial_backoff;
+use rand::thread_rng;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_MAX_DIALING: usize = 4;
+pub const DEFAULT_DIAL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Track the dials in flight and the addresses backing off after dial failures.
+///
+/// The scheduler only decides when an address can be dialed, picking the addresses
+/// and dialing them is done by `NodesManager`.
+#[derive(Debug)]
+pub struct DialScheduler {
+    max_dialing: usize,
+    timeout: Duration,
+    // Addresses being dialed, and when the dial started.
+    dialing: HashMap<SocketAddr, Instant>,
+    // Addresses failed to dial, and the time they can be dialed again.
+    backoff: HashMap<SocketAddr, Instant>,
+}
+
+impl Default for DialScheduler {
+    fn default() -> Self {
+        DialScheduler::new(DEFAULT_MAX_DIALING, DEFAULT_DIAL_TIMEOUT)
+    }
+}
+
+impl DialScheduler {
+    pub fn new(max_dialing: usize, timeout: Duration) -> Self {
+        DialScheduler {
+            max_dialing,
+            timeout,
+            dialing: HashMap::new(),
+            backoff: HashMap::new(),
+        }
+    }
+
+    /// Number of dials can be started now.
+    pub fn free_slots(&self) -> usize {
+        self.max_dialing.saturating_sub(self.dialing.len())
+    }
+
+    pub fn dialing_count(&self) -> usize {
+        self.dialing.len()
+    }
+
+    pub fn dialing(&self) -> impl Iterator<Item = &SocketAddr> {
+        self.dialing.keys()
+    }
+
+    pub fn is_dialing(&self, addr: &SocketAddr) -> bool {
+        self.dialing.contains_key(addr)
+    }
+
+    pub fn is_backing_off(&self, addr: &SocketAddr, now: Instant) -> bool {
+        self.backoff.get(addr).map_or(false, |until| *until > now)
+    }
+
+    pub fn can_dial(&self, addr: &SocketAddr, now: Instant) -> bool {
+        !self.is_dialing(addr) && !self.is_backing_off(addr, now)
+    }
+
+    pub fn start(&mut self, addr: SocketAddr, now: Instant) {
+        self.dialing.insert(addr, now);
+    }
+
+    pub fn succeed(&mut self, addr: &SocketAddr) {
+        self.dialing.remove(addr);
+        self.backoff.remove(addr);
+    }
+
+    /// Record a failed dial, the address can be dialed again after the backoff.
+    pub fn fail(&mut self, addr: SocketAddr, failure_count: u32, now: Instant) -> Duration {
+        self.dialing.remove(&addr);
+        let backoff = dial_backoff(failure_count, &mut thread_rng());
+        self.backoff.insert(addr, now + backoff);
+        backoff
+    }
+
+    /// Forget the address, such as it is removed from the known addresses.
+    pub fn remove(&mut self, addr: &SocketAddr) {
+        self.dialing.remove(addr);
+        self.backoff.remove(addr);
+    }
+
+    /// Remove and return the dials started more than the timeout ago.
+    pub fn take_timed_out(&mut self, now: Instant) -> Vec<SocketAddr> {
+        let timeout = self.timeout;
+        let timed_out: Vec<SocketAddr> = self
+            .dialing
+            .iter()
+            .filter(|(_, started)| now.duration_since(**started) >= timeout)
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in &timed_out {
+            self.dialing.remove(addr);
+        }
+        timed_out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DialScheduler;
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn schedule_dials() {
+        let addr1: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        let addr2: SocketAddr = "10.0.0.2:4000".parse().unwrap();
+        let now = Instant::now();
+        let mut scheduler = DialScheduler::new(2, Duration::from_secs(10));
+
+        scheduler.start(addr1, now);
+        scheduler.start(addr2, now);
+        assert_eq!(scheduler.free_slots(), 0);
+        assert!(!scheduler.can_dial(&addr1, now));
+
+        // Backoff after failure.
+        let backoff = scheduler.fail(addr1, 1, now);
+        assert_eq!(scheduler.free_slots(), 1);
+        assert!(!scheduler.can_dial(&addr1, now));
+        assert!(scheduler.can_dial(&addr1, now + backoff));
+
+        scheduler.succeed(&addr2);
+        assert_eq!(scheduler.free_slots(), 2);
+        assert!(scheduler.can_dial(&addr2, now));
+    }
+
+    #[test]
+    fn dial_timeout() {
+        let addr1: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        let addr2: SocketAddr = "10.0.0.2:4000".parse().unwrap();
+        let now = Instant::now();
+        let mut scheduler = DialScheduler::new(2, Duration::from_secs(10));
+
+        scheduler.start(addr1, now);
+        scheduler.start(addr2, now + Duration::from_secs(5));
+        assert!(scheduler
+            .take_timed_out(now + Duration::from_secs(9))
+            .is_empty());
+        assert_eq!(
+            scheduler.take_timed_out(now + Duration::from_secs(10)),
+            vec![addr1]
+        );
+        assert_eq!(scheduler.dialing_count(), 1);
+    }
+}
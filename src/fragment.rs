@@ -0,0 +1,613 @@
+use crate::citaprotocol::{encode_network_message, NetworkMessage, FLAG_FRAGMENT, FRAME_VERSION_1};
+use byteorder::{ByteOrder, NetworkEndian};
+use bytes::BytesMut;
+use log::error;
+use p2p::SessionId;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::mem;
+use std::time::{Duration, Instant};
+
+/// Messages longer than this are split into fragments, for the peers which can
+/// reassemble them.
+pub const DEFAULT_FRAGMENT_SIZE: usize = 256 * 1024;
+/// A fragment is sent in one frame, so it is kept well below the frame limit.
+pub const MAX_FRAGMENT_SIZE: usize = 4 * 1024 * 1024;
+/// Reassembled messages longer than this are refused.
+pub const DEFAULT_MAX_MESSAGE_LENGTH: usize = 64 * 1024 * 1024;
+/// The unfinished messages of all the sessions take at most this memory.
+pub const DEFAULT_MAX_REASSEMBLY_LENGTH: usize = 256 * 1024 * 1024;
+/// Unfinished messages are dropped after this.
+pub const DEFAULT_REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(60);
+/// A session can have at most this number of unfinished messages.
+pub const MAX_PENDING_PER_SESSION: usize = 4;
+/// The ids of this number of dropped messages are kept for a session, to ignore the
+/// rest of their fragments.
+pub const MAX_ABORTED_PER_SESSION: usize = 16;
+
+// Message id, index and count of the fragments, and length of the message.
+const FRAGMENT_HEADER_LEN: usize = 4 + 2 + 2 + 4;
+
+/// Split the body into fragments of about `fragment_size` bytes, and encode each in a
+/// frame with the flags of the message, such as the codec.
+pub fn encode_fragments(
+    flags: u16,
+    msg_id: u32,
+    key: &str,
+    body: &[u8],
+    fragment_size: usize,
+) -> Vec<Vec<u8>> {
+    if body.len() > u32::max_value() as usize {
+        error!("The message with key {} is too long {}.", key, body.len());
+        return Vec::new();
+    }
+    // The count of the fragments is stored in u16.
+    let max_count = u16::max_value() as usize;
+    let fragment_size = fragment_size.max((body.len() + max_count - 1) / max_count);
+    let chunks: Vec<&[u8]> = if body.is_empty() {
+        vec![body]
+    } else {
+        body.chunks(fragment_size).collect()
+    };
+
+    chunks
+        .iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut fragment = vec![0; FRAGMENT_HEADER_LEN];
+            NetworkEndian::write_u32(&mut fragment[0..4], msg_id);
+            NetworkEndian::write_u16(&mut fragment[4..6], index as u16);
+            NetworkEndian::write_u16(&mut fragment[6..8], chunks.len() as u16);
+            NetworkEndian::write_u32(&mut fragment[8..12], body.len() as u32);
+            fragment.extend_from_slice(chunk);
+
+            let mut buf = BytesMut::new();
+            encode_network_message(
+                &mut buf,
+                FRAME_VERSION_1,
+                flags | FLAG_FRAGMENT,
+                Some((key.to_owned(), fragment)),
+            );
+            buf.to_vec()
+        })
+        .collect()
+}
+
+/// Why a fragment is dropped, with the unfinished message of it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReassemblyError {
+    /// The fragment header is malformed, or does not agree with the other fragments.
+    Malformed,
+    /// The length of the message is over the limit.
+    Oversize(usize),
+    /// The session has too many unfinished messages, or they take too much memory.
+    OverLimit,
+    /// The unfinished messages of all the sessions take too much memory. The peer may
+    /// do nothing wrong.
+    Full,
+}
+
+impl fmt::Display for ReassemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReassemblyError::Malformed => write!(f, "fragment is malformed"),
+            ReassemblyError::Oversize(length) => {
+                write!(f, "fragmented message length {} is over the limit", length)
+            }
+            ReassemblyError::OverLimit => write!(f, "too many unfinished fragmented messages"),
+            ReassemblyError::Full => write!(f, "no room for unfinished fragmented messages"),
+        }
+    }
+}
+
+impl Error for ReassemblyError {}
+
+#[derive(Clone, Debug)]
+struct Pending {
+    version: u8,
+    flags: u16,
+    key: String,
+    length: usize,
+    // Length of the fragments but the last one.
+    fragment_len: usize,
+    chunks: Vec<Option<Vec<u8>>>,
+    received: usize,
+    bytes: usize,
+    started: Instant,
+}
+
+impl Pending {
+    // Memory taken by the message, the slots of the fragments count too.
+    fn size(&self) -> usize {
+        self.bytes + slots_size(self.chunks.len())
+    }
+}
+
+fn slots_size(count: usize) -> usize {
+    count * mem::size_of::<Option<Vec<u8>>>()
+}
+
+// The length of the fragments but the last one, if the fragment agrees with the count
+// and the length of the message. All the fragments but the last one have the same
+// length, and the last one is not empty unless the message is.
+fn fragment_len(index: usize, count: usize, length: usize, chunk_len: usize) -> Option<usize> {
+    if count == 1 {
+        return if chunk_len == length {
+            Some(length)
+        } else {
+            None
+        };
+    }
+    let fragment_len = if index + 1 < count {
+        chunk_len
+    } else {
+        if chunk_len == 0 || chunk_len > length || (length - chunk_len) % (count - 1) != 0 {
+            return None;
+        }
+        (length - chunk_len) / (count - 1)
+    };
+    if fragment_len > 0 && (length + fragment_len - 1) / fragment_len == count {
+        Some(fragment_len)
+    } else {
+        None
+    }
+}
+
+/// Reassemble the fragmented messages of the sessions.
+///
+/// A session can keep `MAX_PENDING_PER_SESSION` unfinished messages of twice the message
+/// limit, so a large message does not stop the next one, and all the sessions share
+/// a total limit. Once an unfinished message is dropped, the rest of its fragments are
+/// ignored until the timeout, so they do not start a message which never finishes.
+#[derive(Clone, Debug)]
+pub struct Reassembler {
+    max_message_len: usize,
+    max_reassembly_len: usize,
+    timeout: Duration,
+    pending: HashMap<(SessionId, u32), Pending>,
+    // The messages dropped, and when.
+    aborted: HashMap<(SessionId, u32), Instant>,
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Reassembler::new(
+            DEFAULT_MAX_MESSAGE_LENGTH,
+            DEFAULT_MAX_REASSEMBLY_LENGTH,
+            DEFAULT_REASSEMBLY_TIMEOUT,
+        )
+    }
+}
+
+impl Reassembler {
+    pub fn new(max_message_len: usize, max_reassembly_len: usize, timeout: Duration) -> Self {
+        Reassembler {
+            max_message_len,
+            max_reassembly_len,
+            timeout,
+            pending: HashMap::new(),
+            aborted: HashMap::new(),
+        }
+    }
+
+    pub fn max_message_len(&self) -> usize {
+        self.max_message_len
+    }
+
+    /// Add a fragment from the session, and return the message once all the fragments
+    /// are in. The unfinished message is dropped on an error, and the rest of its
+    /// fragments are ignored.
+    pub fn insert(
+        &mut self,
+        session_id: SessionId,
+        msg: NetworkMessage,
+        now: Instant,
+    ) -> Result<Option<NetworkMessage>, ReassemblyError> {
+        if msg.body.len() < FRAGMENT_HEADER_LEN {
+            return Err(ReassemblyError::Malformed);
+        }
+        let msg_id = NetworkEndian::read_u32(&msg.body[0..4]);
+        let index = NetworkEndian::read_u16(&msg.body[4..6]) as usize;
+        let count = NetworkEndian::read_u16(&msg.body[6..8]) as usize;
+        let length = NetworkEndian::read_u32(&msg.body[8..12]) as usize;
+        let chunk = &msg.body[FRAGMENT_HEADER_LEN..];
+        let id = (session_id, msg_id);
+        if self.aborted.contains_key(&id) {
+            return Ok(None);
+        }
+
+        if length > self.max_message_len {
+            self.abort(id, now);
+            return Err(ReassemblyError::Oversize(length));
+        }
+        if index >= count {
+            self.abort(id, now);
+            return Err(ReassemblyError::Malformed);
+        }
+        let fragment_len = match fragment_len(index, count, length, chunk.len()) {
+            Some(fragment_len) => fragment_len,
+            None => {
+                self.abort(id, now);
+                return Err(ReassemblyError::Malformed);
+            }
+        };
+
+        // The memory taken by the fragment, and by the slots of a new message.
+        let size = if self.pending.contains_key(&id) {
+            chunk.len()
+        } else {
+            if self.pending_count(session_id) >= MAX_PENDING_PER_SESSION {
+                self.abort(id, now);
+                return Err(ReassemblyError::OverLimit);
+            }
+            chunk.len() + slots_size(count)
+        };
+        if self.pending_size(Some(session_id)) + size > 2 * self.max_message_len {
+            self.abort(id, now);
+            return Err(ReassemblyError::OverLimit);
+        }
+        if self.pending_size(None) + size > self.max_reassembly_len {
+            self.abort(id, now);
+            return Err(ReassemblyError::Full);
+        }
+
+        let complete = {
+            let pending = self.pending.entry(id).or_insert_with(|| Pending {
+                version: msg.version,
+                flags: msg.flags,
+                key: msg.key.clone(),
+                length,
+                fragment_len,
+                chunks: vec![None; count],
+                received: 0,
+                bytes: 0,
+                started: now,
+            });
+            if pending.flags != msg.flags
+                || pending.key != msg.key
+                || pending.length != length
+                || pending.fragment_len != fragment_len
+                || pending.chunks.len() != count
+                || pending.chunks[index].is_some()
+                || pending.bytes + chunk.len() > length
+            {
+                None
+            } else {
+                pending.chunks[index] = Some(chunk.to_vec());
+                pending.received += 1;
+                pending.bytes += chunk.len();
+                Some(pending.received == count)
+            }
+        };
+
+        match complete {
+            None => {
+                self.abort(id, now);
+                Err(ReassemblyError::Malformed)
+            }
+            Some(false) => Ok(None),
+            Some(true) => {
+                let pending = self.pending.remove(&id).unwrap();
+                if pending.bytes != pending.length {
+                    return Err(ReassemblyError::Malformed);
+                }
+                let mut body = Vec::with_capacity(pending.length);
+                for chunk in pending.chunks.into_iter().flatten() {
+                    body.extend_from_slice(&chunk);
+                }
+                Ok(Some(NetworkMessage {
+                    version: pending.version,
+                    flags: pending.flags & !FLAG_FRAGMENT,
+                    key: pending.key,
+                    body,
+                }))
+            }
+        }
+    }
+
+    /// Drop the unfinished messages older than the timeout, and return the number dropped.
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let timeout = self.timeout;
+        let before = self.pending.len();
+        self.pending
+            .retain(|_, pending| now.duration_since(pending.started) < timeout);
+        self.aborted
+            .retain(|_, aborted_at| now.duration_since(*aborted_at) < timeout);
+        before - self.pending.len()
+    }
+
+    /// Drop the unfinished messages of a closed session.
+    pub fn remove_session(&mut self, session_id: SessionId) {
+        self.pending.retain(|(id, _), _| *id != session_id);
+        self.aborted.retain(|(id, _), _| *id != session_id);
+    }
+
+    // Drop the unfinished message, and remember it to ignore the rest of its fragments.
+    // The oldest one of the session is forgotten if it has too many.
+    fn abort(&mut self, id: (SessionId, u32), now: Instant) {
+        self.pending.remove(&id);
+        let session_id = id.0;
+        let aborted = self.aborted.iter().filter(|((id, _), _)| *id == session_id);
+        if aborted.clone().count() >= MAX_ABORTED_PER_SESSION {
+            let oldest = aborted
+                .min_by_key(|(_, aborted_at)| **aborted_at)
+                .map(|(id, _)| *id);
+            if let Some(oldest) = oldest {
+                self.aborted.remove(&oldest);
+            }
+        }
+        self.aborted.insert(id, now);
+    }
+
+    fn pending_count(&self, session_id: SessionId) -> usize {
+        self.pending
+            .keys()
+            .filter(|(id, _)| *id == session_id)
+            .count()
+    }
+
+    // Memory taken by the unfinished messages of the session, or of all the sessions.
+    fn pending_size(&self, session_id: Option<SessionId>) -> usize {
+        self.pending
+            .iter()
+            .filter(|((id, _), _)| session_id.map_or(true, |session_id| *id == session_id))
+            .map(|(_, pending)| pending.size())
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        encode_fragments, Reassembler, ReassemblyError, MAX_ABORTED_PER_SESSION,
+        MAX_PENDING_PER_SESSION,
+    };
+    use crate::citaprotocol::{
+        decode_network_message, NetworkMessage, FLAG_FRAGMENT, FRAME_VERSION_1,
+    };
+    use byteorder::{ByteOrder, NetworkEndian};
+    use bytes::BytesMut;
+    use std::time::{Duration, Instant};
+
+    fn fragments(msg_id: u32, body: &[u8], fragment_size: usize) -> Vec<NetworkMessage> {
+        encode_fragments(0, msg_id, "key", body, fragment_size)
+            .into_iter()
+            .map(|frame| {
+                let mut buf = BytesMut::from(frame);
+                decode_network_message(&mut buf).unwrap().unwrap()
+            })
+            .collect()
+    }
+
+    // A fragment with the header given, and a chunk of zeros.
+    fn fragment(
+        msg_id: u32,
+        index: u16,
+        count: u16,
+        length: u32,
+        chunk_len: usize,
+    ) -> NetworkMessage {
+        let mut body = vec![0; 12 + chunk_len];
+        NetworkEndian::write_u32(&mut body[0..4], msg_id);
+        NetworkEndian::write_u16(&mut body[4..6], index);
+        NetworkEndian::write_u16(&mut body[6..8], count);
+        NetworkEndian::write_u32(&mut body[8..12], length);
+        NetworkMessage {
+            version: FRAME_VERSION_1,
+            flags: FLAG_FRAGMENT,
+            key: "key".to_owned(),
+            body,
+        }
+    }
+
+    #[test]
+    fn reassemble_interleaved() {
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new(1024, 1 << 20, Duration::from_secs(60));
+        let first: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let second = vec![7; 250];
+        let first_fragments = fragments(1, &first, 100);
+        let second_fragments = fragments(2, &second, 100);
+        assert_eq!(first_fragments.len(), 10);
+        assert!(first_fragments
+            .iter()
+            .all(|msg| msg.flags & FLAG_FRAGMENT != 0));
+
+        // Fragments of two messages, out of order.
+        let mut messages = Vec::new();
+        let mut first_fragments = first_fragments.into_iter().rev();
+        for fragment in second_fragments {
+            let msg = reassembler
+                .insert(1, first_fragments.next().unwrap(), now)
+                .unwrap();
+            messages.extend(msg);
+            messages.extend(reassembler.insert(1, fragment, now).unwrap());
+        }
+        for fragment in first_fragments {
+            messages.extend(reassembler.insert(1, fragment, now).unwrap());
+        }
+
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].body, second);
+        assert_eq!(messages[1].body, first);
+        assert_eq!(messages[1].key, "key");
+        assert_eq!(messages[1].flags, 0);
+        assert!(reassembler.pending.is_empty());
+
+        // An empty body is one fragment.
+        let empty = fragments(3, &[], 100);
+        assert_eq!(empty.len(), 1);
+        let msg = reassembler.insert(1, empty[0].clone(), now).unwrap();
+        assert_eq!(msg.unwrap().body, Vec::<u8>::new());
+    }
+
+    #[test]
+    fn reject_fragments() {
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new(1024, 1 << 20, Duration::from_secs(60));
+
+        let too_long = fragments(1, &[0; 2000], 100);
+        assert_eq!(
+            reassembler.insert(1, too_long[0].clone(), now),
+            Err(ReassemblyError::Oversize(2000))
+        );
+
+        // A duplicate fragment drops the message, and the rest of it is ignored.
+        let body = fragments(2, &[0; 300], 100);
+        assert_eq!(reassembler.insert(1, body[0].clone(), now), Ok(None));
+        assert_eq!(
+            reassembler.insert(1, body[0].clone(), now),
+            Err(ReassemblyError::Malformed)
+        );
+        assert_eq!(reassembler.insert(1, body[1].clone(), now), Ok(None));
+        assert_eq!(reassembler.insert(1, body[2].clone(), now), Ok(None));
+        assert!(reassembler.pending.is_empty());
+
+        let mut short = body[1].clone();
+        short.body.truncate(4);
+        assert_eq!(
+            reassembler.insert(1, short, now),
+            Err(ReassemblyError::Malformed)
+        );
+
+        // At most twice the message limit is kept for a session.
+        for msg_id in 3..5 {
+            let body = fragments(msg_id, &[0; 1000], 900);
+            assert_eq!(reassembler.insert(1, body[0].clone(), now), Ok(None));
+        }
+        let body = fragments(5, &[0; 1000], 900);
+        assert_eq!(
+            reassembler.insert(1, body[0].clone(), now),
+            Err(ReassemblyError::OverLimit)
+        );
+        // Other sessions are not limited by it.
+        assert_eq!(reassembler.insert(2, body[0].clone(), now), Ok(None));
+
+        reassembler.remove_session(1);
+        assert_eq!(reassembler.pending.len(), 1);
+        assert_eq!(reassembler.expire(now + Duration::from_secs(59)), 0);
+        assert_eq!(reassembler.expire(now + Duration::from_secs(60)), 1);
+    }
+
+    #[test]
+    fn validate_fragment_count() {
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new(1024, 1 << 20, Duration::from_secs(60));
+        let malformed = [
+            // The count does not agree with the length.
+            fragment(1, 0, 4, 300, 100),
+            fragment(2, 0, 0, 300, 100),
+            fragment(3, 2, 3, 300, 150),
+            // An empty last fragment.
+            fragment(4, 3, 4, 300, 0),
+            fragment(5, 0, 2, 0, 0),
+            fragment(6, 0, 1, 300, 100),
+        ];
+        for msg in malformed.iter() {
+            assert_eq!(
+                reassembler.insert(1, msg.clone(), now),
+                Err(ReassemblyError::Malformed)
+            );
+        }
+
+        // The fragments but the last one have the same length.
+        assert_eq!(
+            reassembler.insert(1, fragment(7, 0, 3, 300, 100), now),
+            Ok(None)
+        );
+        assert_eq!(
+            reassembler.insert(1, fragment(7, 1, 3, 300, 120), now),
+            Err(ReassemblyError::Malformed)
+        );
+        assert!(reassembler.pending.is_empty());
+
+        // The last fragment first.
+        assert_eq!(
+            reassembler.insert(1, fragment(8, 2, 3, 250, 50), now),
+            Ok(None)
+        );
+        assert_eq!(
+            reassembler.insert(1, fragment(8, 0, 3, 250, 100), now),
+            Ok(None)
+        );
+        let msg = reassembler.insert(1, fragment(8, 1, 3, 250, 100), now);
+        assert_eq!(msg.unwrap().unwrap().body.len(), 250);
+    }
+
+    #[test]
+    fn limit_pending_messages() {
+        let now = Instant::now();
+
+        // The slots of the fragments count.
+        let mut reassembler = Reassembler::new(100_000, 1 << 30, Duration::from_secs(60));
+        assert_eq!(
+            reassembler.insert(1, fragment(100, 0, 60_000, 60_000, 1), now),
+            Err(ReassemblyError::OverLimit)
+        );
+
+        // A few messages of a session.
+        for msg_id in 0..MAX_PENDING_PER_SESSION as u32 {
+            let msg = fragment(msg_id, 0, 2, 200, 100);
+            assert_eq!(reassembler.insert(1, msg, now), Ok(None));
+        }
+        assert_eq!(
+            reassembler.insert(1, fragment(10, 0, 2, 200, 100), now),
+            Err(ReassemblyError::OverLimit)
+        );
+        // The next fragments of the unfinished ones are still taken.
+        let msg = reassembler.insert(1, fragment(0, 1, 2, 200, 100), now);
+        assert_eq!(msg.unwrap().unwrap().body.len(), 200);
+        assert_eq!(
+            reassembler.insert(2, fragment(10, 0, 2, 200, 100), now),
+            Ok(None)
+        );
+
+        // All the sessions share the total limit.
+        let mut reassembler = Reassembler::new(1024, 1500, Duration::from_secs(60));
+        let body = fragments(1, &[0; 1000], 900);
+        assert_eq!(reassembler.insert(1, body[0].clone(), now), Ok(None));
+        assert_eq!(
+            reassembler.insert(2, body[0].clone(), now),
+            Err(ReassemblyError::Full)
+        );
+        reassembler.remove_session(1);
+        // The rest of the message dropped does not start a message which never finishes.
+        assert_eq!(reassembler.insert(2, body[1].clone(), now), Ok(None));
+        assert!(reassembler.pending.is_empty());
+        let next = fragments(2, &[0; 1000], 900);
+        assert_eq!(reassembler.insert(2, next[0].clone(), now), Ok(None));
+
+        // A message dropped is forgotten after the timeout.
+        reassembler.expire(now + Duration::from_secs(60));
+        assert_eq!(reassembler.insert(2, body[1].clone(), now), Ok(None));
+        assert_eq!(reassembler.pending.len(), 1);
+    }
+
+    #[test]
+    fn limit_aborted_messages() {
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new(1024, 1 << 20, Duration::from_secs(60));
+        for msg_id in 0..=MAX_ABORTED_PER_SESSION as u32 {
+            let at = now + Duration::from_secs(u64::from(msg_id));
+            assert_eq!(
+                reassembler.insert(1, fragment(msg_id, 0, 4, 300, 100), at),
+                Err(ReassemblyError::Malformed)
+            );
+        }
+        assert_eq!(reassembler.aborted.len(), MAX_ABORTED_PER_SESSION);
+        // The oldest one is forgotten.
+        assert!(!reassembler.aborted.contains_key(&(1, 0)));
+        assert!(reassembler.aborted.contains_key(&(1, 1)));
+
+        reassembler.remove_session(1);
+        assert!(reassembler.aborted.is_empty());
+    }
+
+    #[test]
+    fn limit_fragment_count() {
+        let body = vec![0; 70_000];
+        let frames = encode_fragments(0, 1, "key", &body, 1);
+        assert_eq!(frames.len(), 35_000);
+    }
+}
@@ -0,0 +1,234 @@
+//! Persistent node identity.
+//!
+//! The key file holds a single line `<key type>:<hex encoded secret key>`,
+//! e.g. `secp256k1:0f1e...`. Only `secp256k1` is supported for now.
+
+use log::{info, warn};
+use p2p::SecioKeyPair;
+use rand::{thread_rng, Rng};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+pub const KEY_TYPE_SECP256K1: &str = "secp256k1";
+const SECRET_KEY_LEN: usize = 32;
+
+#[derive(Debug)]
+pub enum KeyFileError {
+    Io(io::Error),
+    /// The file does not look like `<key type>:<hex>`.
+    Corrupt(String),
+    /// The file holds a key of a type we cannot use.
+    UnsupportedKeyType(String),
+    /// The secret key is well formed, but not a valid key of its type.
+    InvalidKey(String),
+}
+
+impl fmt::Display for KeyFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyFileError::Io(err) => write!(f, "io error: {}", err),
+            KeyFileError::Corrupt(reason) => write!(f, "corrupt key file: {}", reason),
+            KeyFileError::UnsupportedKeyType(ty) => write!(
+                f,
+                "unsupported key type {:?}, expect {:?}",
+                ty, KEY_TYPE_SECP256K1
+            ),
+            KeyFileError::InvalidKey(reason) => write!(f, "invalid secret key: {}", reason),
+        }
+    }
+}
+
+impl From<io::Error> for KeyFileError {
+    fn from(err: io::Error) -> Self {
+        KeyFileError::Io(err)
+    }
+}
+
+/// Load the node key from `path`, or generate and save a new one if the file does not exist.
+pub fn load_or_generate_key<P: AsRef<Path>>(path: P) -> Result<SecioKeyPair, KeyFileError> {
+    let path = path.as_ref();
+    if path.exists() {
+        info!("Load node key from {:?}", path);
+        load_key(path)
+    } else {
+        info!("Node key file {:?} not found, generate a new one", path);
+        generate_key(path)
+    }
+}
+
+pub fn load_key(path: &Path) -> Result<SecioKeyPair, KeyFileError> {
+    check_permissions(path);
+
+    let mut content = String::new();
+    File::open(path)?.read_to_string(&mut content)?;
+
+    let content = content.trim();
+    let mut parts = content.splitn(2, ':');
+    let (ty, secret) = match (parts.next(), parts.next()) {
+        (Some(ty), Some(secret)) => (ty.trim(), secret.trim()),
+        _ => {
+            return Err(KeyFileError::Corrupt(
+                "expect `<key type>:<hex secret key>`".to_owned(),
+            ));
+        }
+    };
+
+    if ty != KEY_TYPE_SECP256K1 {
+        return Err(KeyFileError::UnsupportedKeyType(ty.to_owned()));
+    }
+
+    let secret = hex::decode(secret).map_err(|err| KeyFileError::Corrupt(err.to_string()))?;
+    if secret.len() != SECRET_KEY_LEN {
+        return Err(KeyFileError::Corrupt(format!(
+            "secret key length {}, expect {}",
+            secret.len(),
+            SECRET_KEY_LEN
+        )));
+    }
+
+    SecioKeyPair::secp256k1_raw_key(&secret)
+        .map_err(|err| KeyFileError::InvalidKey(format!("{:?}", err)))
+}
+
+pub fn generate_key(path: &Path) -> Result<SecioKeyPair, KeyFileError> {
+    let mut rng = thread_rng();
+    let mut secret = [0u8; SECRET_KEY_LEN];
+
+    // A random 32 bytes is out of the curve order with a negligible probability,
+    // just try again in that case.
+    let key_pair = loop {
+        rng.fill_bytes(&mut secret);
+        if let Ok(key_pair) = SecioKeyPair::secp256k1_raw_key(&secret) {
+            break key_pair;
+        }
+    };
+
+    if let Some(dir) = path.parent() {
+        if !dir.as_os_str().is_empty() {
+            fs::create_dir_all(dir)?;
+        }
+    }
+
+    let mut file = create_private_file(path)?;
+    writeln!(file, "{}:{}", KEY_TYPE_SECP256K1, hex::encode(&secret[..]))?;
+    file.sync_all()?;
+
+    Ok(key_pair)
+}
+
+#[cfg(unix)]
+fn create_private_file(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+}
+
+#[cfg(not(unix))]
+fn create_private_file(path: &Path) -> io::Result<File> {
+    OpenOptions::new().write(true).create_new(true).open(path)
+}
+
+#[cfg(unix)]
+fn check_permissions(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Ok(metadata) = fs::metadata(path) {
+        let mode = metadata.permissions().mode();
+        if mode & 0o077 != 0 {
+            warn!(
+                "Node key file {:?} is accessible by other users (mode {:o}), \
+                 should be 600",
+                path,
+                mode & 0o777
+            );
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path) {}
+
+#[cfg(test)]
+mod tests {
+    use super::{load_key, load_or_generate_key, KeyFileError};
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn generate_then_load() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("node.key");
+
+        let generated = load_or_generate_key(&path).unwrap();
+        let loaded = load_or_generate_key(&path).unwrap();
+        assert_eq!(generated.to_peer_id(), loaded.to_peer_id());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn generated_key_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("node.key");
+        load_or_generate_key(&path).unwrap();
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn corrupt_key_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("node.key");
+
+        fs::write(&path, "not a key").unwrap();
+        match load_key(&path) {
+            Err(KeyFileError::Corrupt(_)) => {}
+            _ => panic!("unexpected result"),
+        }
+
+        fs::write(&path, "secp256k1:zz").unwrap();
+        match load_key(&path) {
+            Err(KeyFileError::Corrupt(_)) => {}
+            _ => panic!("unexpected result"),
+        }
+
+        fs::write(&path, "secp256k1:0102").unwrap();
+        match load_key(&path) {
+            Err(KeyFileError::Corrupt(_)) => {}
+            _ => panic!("unexpected result"),
+        }
+    }
+
+    #[test]
+    fn wrong_key_type() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("node.key");
+
+        fs::write(&path, format!("ed25519:{}", "01".repeat(32))).unwrap();
+        match load_key(&path) {
+            Err(KeyFileError::UnsupportedKeyType(ty)) => assert_eq!(ty, "ed25519"),
+            _ => panic!("unexpected result"),
+        }
+    }
+
+    #[test]
+    fn invalid_secret_key() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("node.key");
+
+        // Zero is not a valid secp256k1 secret key.
+        fs::write(&path, format!("secp256k1:{}", "00".repeat(32))).unwrap();
+        match load_key(&path) {
+            Err(KeyFileError::InvalidKey(_)) => {}
+            _ => panic!("unexpected result"),
+        }
+    }
+}
@@ -1,18 +1,36 @@
+pub mod addr_selection;
+pub mod address_book;
+pub mod allowlist;
+pub mod ban_list;
 pub mod citaprotocol;
+pub mod compression;
 pub mod config;
+pub mod config_watcher;
+pub mod dial_scheduler;
+pub mod fragment;
+pub mod identity;
+pub mod metrics;
 pub mod mq_client;
 pub mod network;
 pub mod node_manager;
 pub mod p2p_protocol;
+pub mod peer_score;
+pub mod persist;
+pub mod resolver;
+pub mod session_registry;
 pub mod synchronizer;
 
 use crate::config::NetConfig;
+use crate::config_watcher::ConfigWatcher;
+use crate::fragment::Reassembler;
+use crate::identity::load_or_generate_key;
 use crate::mq_client::MqClient;
 use crate::network::{LocalMessage, Network};
-use crate::node_manager::{BroadcastReq, NodesManager, DEFAULT_PORT};
+use crate::node_manager::{BroadcastReq, NodesManager, ShutdownReq};
 use crate::p2p_protocol::{
-    node_discovery::{DiscoveryProtocolMeta, NodesAddressManager},
-    transfer::TransferProtocolMeta,
+    identify::{IdentifyProtocolMeta, IDENTIFY_PROTOCOL_ID},
+    node_discovery::{DiscoveryProtocolMeta, NodesAddressManager, DISCOVERY_PROTOCOL_ID},
+    transfer::{TransferProtocolMeta, TRANSFER_PROTOCOL_ID},
     SHandle,
 };
 use crate::synchronizer::Synchronizer;
@@ -22,9 +40,10 @@ use futures::prelude::*;
 use libproto::router::{MsgType, RoutingKey, SubModules};
 use libproto::routing_key;
 use libproto::{Message, TryFrom};
-use log::{debug, info, trace};
-use p2p::{builder::ServiceBuilder, SecioKeyPair};
+use log::{debug, error, info, trace, warn};
+use p2p::{builder::ServiceBuilder, multiaddr::Multiaddr, SecioKeyPair};
 use pubsub::start_pubsub;
+use std::process;
 use std::sync::mpsc::channel;
 use std::thread;
 use util::micro_service_init;
@@ -52,6 +71,12 @@ fn main() {
     debug!("config path {:?}", config_path);
     let config = NetConfig::new(&config_path);
     debug!("network config is {:?}", config);
+    if let Err(errors) = config.validate() {
+        for err in errors {
+            error!("Invalid network config: {}", err);
+        }
+        process::exit(1);
+    }
     // <<<< End init config
 
     // >>>> Init pubsub
@@ -94,6 +119,20 @@ fn main() {
     let mq_client = MqClient::new(ctx_pub_auth, ctx_pub_consensus, ctx_pub);
     // <<<< End init pubsub
 
+    // >>>> Init node key
+    let key_pair = match config.private_key_path {
+        Some(ref path) => load_or_generate_key(path).unwrap_or_else(|err| {
+            error!("Failed to load node key from {:?}: {}", path, err);
+            process::exit(1);
+        }),
+        None => {
+            warn!("private_key_path is not set, use a temporary node key");
+            SecioKeyPair::secp256k1_generated()
+        }
+    };
+    info!("Node peer id: {}", key_pair.to_peer_id().to_base58());
+    // <<<< End init node key
+
     // >>>> Init p2p protocols
     let mut nodes_mgr = NodesManager::from_config(config.clone());
     let mut synchronizer_mgr = Synchronizer::new(mq_client.clone(), nodes_mgr.client());
@@ -102,18 +141,53 @@ fn main() {
         nodes_mgr.client(),
         synchronizer_mgr.client(),
     );
-    let discovery_meta =
-        DiscoveryProtocolMeta::new(0, NodesAddressManager::new(nodes_mgr.client()));
-    let transfer_meta = TransferProtocolMeta::new(1, network_mgr.client());
+    let discovery_meta = DiscoveryProtocolMeta::new(
+        DISCOVERY_PROTOCOL_ID,
+        NodesAddressManager::new(nodes_mgr.client()),
+    );
+    let transfer_meta = TransferProtocolMeta::new(
+        TRANSFER_PROTOCOL_ID,
+        network_mgr.client(),
+        nodes_mgr.client(),
+        Reassembler::new(
+            config.max_message_length(),
+            config.max_reassembly_length(),
+            config.reassembly_timeout(),
+        ),
+        nodes_mgr.allowlist(),
+    );
+    let identify_meta = IdentifyProtocolMeta::new(
+        IDENTIFY_PROTOCOL_ID,
+        nodes_mgr.client(),
+        config.learn_external_addr(),
+    );
 
-    let mut service = ServiceBuilder::default()
-        .insert_protocol(discovery_meta)
+    // Nodes only know each other from the config in validator-only mode.
+    let mut builder = ServiceBuilder::default();
+    if config.is_validator_only() {
+        info!("Validator-only mode, the discovery protocol is off");
+    } else {
+        builder = builder.insert_protocol(discovery_meta);
+    }
+    let mut service = builder
         .insert_protocol(transfer_meta)
+        .insert_protocol(identify_meta)
         .forever(true)
-        .key_pair(SecioKeyPair::secp256k1_generated())
+        .key_pair(key_pair)
         .build(SHandle::new(nodes_mgr.client()));
-    let addr = format!("/ip4/127.0.0.1/tcp/{}", config.port.unwrap_or(DEFAULT_PORT));
-    let _ = service.listen(&addr.parse().unwrap());
+    for addr in config.listen_addrs() {
+        let multiaddr: Multiaddr = addr.parse().unwrap_or_else(|err| {
+            error!("Invalid listen address {:?}: {:?}", addr, err);
+            process::exit(1);
+        });
+        match service.listen(&multiaddr) {
+            Ok(listen_addr) => info!("Listen on {}", listen_addr),
+            Err(err) => {
+                error!("Failed to listen on {}: {:?}", multiaddr, err);
+                process::exit(1);
+            }
+        }
+    }
     nodes_mgr.set_service_task_sender(service.control().clone());
     // <<<< End init p2p protocols
 
@@ -147,7 +221,23 @@ fn main() {
         network_client.handle_local_message(msg);
     });
 
-    thread::spawn(move || nodes_mgr.run());
+    // Save the network data before exit
+    let nodes_manager_client = nodes_mgr.client();
+    ctrlc::set_handler(move || {
+        info!("Receive exit signal, shutdown");
+        nodes_manager_client.shutdown(ShutdownReq::new());
+    })
+    .expect("Set exit signal handler failed");
+
+    let mut config_watcher = ConfigWatcher::new(config_path, config, nodes_mgr.client());
+    thread::spawn(move || config_watcher.run());
+
+    // Exit once the nodes manager has saved the data.
+    thread::spawn(move || {
+        nodes_mgr.run();
+        info!("Network data saved, exit");
+        process::exit(0);
+    });
     thread::spawn(move || network_mgr.run());
     thread::spawn(move || synchronizer_mgr.run());
     tokio::run(service.for_each(|_| Ok(())));
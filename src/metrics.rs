@@ -0,0 +1,70 @@
+use crate::persist::write_file;
+use std::fmt::Write;
+use std::io;
+use std::path::Path;
+
+/// The metrics are written to this file in the data directory, in the Prometheus
+/// text format, so they can be collected by the node exporter textfile collector.
+pub const METRICS_FILE: &str = "network_metrics.prom";
+
+#[derive(Clone, Debug, Default)]
+pub struct NetworkMetrics {
+    // Sessions disconnected because the public key is not in the allowlist,
+    // or the node is not configured in validator-only mode.
+    pub rejected_unauthorized: u64,
+    // Sessions disconnected because the address or public key is banned.
+    pub rejected_banned: u64,
+    // Inbound sessions disconnected because the inbound sessions are full.
+    pub rejected_full: u64,
+    // Frames dropped because the checksum does not match.
+    pub bad_checksums: u64,
+}
+
+impl NetworkMetrics {
+    pub fn to_prometheus(&self) -> String {
+        let mut text = String::new();
+        for (reason, count) in &[
+            ("unauthorized", self.rejected_unauthorized),
+            ("banned", self.rejected_banned),
+            ("full", self.rejected_full),
+        ] {
+            let _ = writeln!(
+                text,
+                "cita_network_rejected_sessions_total{{reason=\"{}\"}} {}",
+                reason, count
+            );
+        }
+        let _ = writeln!(
+            text,
+            "cita_network_bad_checksums_total {}",
+            self.bad_checksums
+        );
+        text
+    }
+}
+
+pub fn save_metrics(path: &Path, metrics: &NetworkMetrics) -> io::Result<()> {
+    write_file(path, metrics.to_prometheus().as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NetworkMetrics;
+
+    #[test]
+    fn prometheus_text() {
+        let metrics = NetworkMetrics {
+            rejected_unauthorized: 3,
+            rejected_banned: 2,
+            rejected_full: 1,
+            bad_checksums: 5,
+        };
+        assert_eq!(
+            metrics.to_prometheus(),
+            "cita_network_rejected_sessions_total{reason=\"unauthorized\"} 3\n\
+             cita_network_rejected_sessions_total{reason=\"banned\"} 2\n\
+             cita_network_rejected_sessions_total{reason=\"full\"} 1\n\
+             cita_network_bad_checksums_total 5\n"
+        );
+    }
+}
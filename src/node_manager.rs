@@ -1,71 +1,274 @@
-use crate::citaprotocol::pubsub_message_to_network_message;
-use crate::config::NetConfig;
-use bytes::BytesMut;
+use crate::addr_selection::{bucket, select_addrs, Bucket, DEFAULT_MAX_ADDRS_PER_BUCKET};
+use crate::address_book::{
+    load_address_book, save_address_book, AddrInfo, ADDRESS_BOOK_FILE, DEFAULT_SCORE,
+};
+use crate::allowlist::{normalize_key, Allowlist};
+use crate::ban_list::{BanList, BanTarget, BAN_LIST_FILE};
+use crate::citaprotocol::{
+    encode_network_message, FLAG_CHECKSUM, FRAME_VERSION_1, FRAME_VERSION_LEGACY,
+};
+use crate::compression::{
+    compress_body, encode_message, negotiate, Capabilities, Compression, CAPABILITIES_KEY,
+    DEFAULT_COMPRESSION_THRESHOLD,
+};
+use crate::config::{NetConfig, PeerAddress};
+use crate::dial_scheduler::{DialScheduler, DEFAULT_DIAL_TIMEOUT, DEFAULT_MAX_DIALING};
+use crate::fragment::{encode_fragments, DEFAULT_FRAGMENT_SIZE};
+use crate::metrics::{save_metrics, NetworkMetrics, METRICS_FILE};
+use crate::p2p_protocol::transfer::{TRANSFER_PROTOCOL_ID, TRANSFER_VERSION_CAPABILITIES};
+use crate::peer_score::{self, Misbehavior, ScoreEvent, DIAL_RETRY_BUDGET, MAX_SCORE, MIN_SCORE};
+use crate::resolver::{Resolver, SystemResolver};
+use crate::session_registry::{SessionInfo, SessionRegistry};
+use bytes::{Bytes, BytesMut};
 use crossbeam_channel;
 use crossbeam_channel::{select, tick, unbounded};
 use discovery::RawAddr;
 use fnv::FnvHashMap;
 use libproto::{Message as ProtoMessage, TryInto};
-use log::{debug, trace, warn};
-use p2p::{context::ServiceControl, multiaddr::ToMultiaddr, SessionId};
+use log::{debug, info, trace, warn};
+use p2p::{
+    context::ServiceControl,
+    multiaddr::{Multiaddr, ToMultiaddr},
+    utils::multiaddr_to_socketaddr,
+    ProtocolId, SessionId, SessionType,
+};
+use rand::thread_rng;
 use std::{
-    collections::HashMap,
-    net::SocketAddr,
-    str::FromStr,
+    collections::{HashMap, HashSet, VecDeque},
+    io,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    thread,
     time::{Duration, Instant},
 };
 
 pub const DEFAULT_MAX_CONNECTS: usize = 4;
+pub const DEFAULT_MAX_INBOUND: usize = 8;
+pub const DEFAULT_MAX_KNOWN_ADDRS: usize = 1000;
+pub const DEFAULT_MAX_ADDRS_PER_SESSION: usize = 100;
 pub const DEFAULT_PORT: usize = 4000;
 pub const CHECK_CONNECTED_NODES: Duration = Duration::from_secs(3);
+pub const RESOLVE_PEER_HOSTS: Duration = Duration::from_secs(60);
+pub const EXPORT_METRICS: Duration = Duration::from_secs(15);
+pub const SAVE_ADDRESS_BOOK: Duration = Duration::from_secs(60);
+// Connected peers gain score for every interval the session stays open.
+pub const SESSION_UPTIME_INTERVAL: Duration = Duration::from_secs(60);
+pub const DEFAULT_MISBEHAVE_BAN_SCORE: i32 = 40;
+pub const DEFAULT_MISBEHAVE_BAN_SECS: u64 = 3600;
+// The score of an IP without known addresses is forgotten after this long without updates,
+// unless a session on the IP is still open.
+pub const IP_SCORE_TTL: Duration = Duration::from_secs(3600);
+// The addresses gossiped from an IP are counted in windows of this long, so a node
+// can not bypass the limit by reconnecting.
+pub const DISCOVERED_ADDRS_WINDOW: Duration = Duration::from_secs(3600);
+// An observed address is trusted after being reported by outbound sessions to peers
+// in this number of distinct buckets, so a few colluding hosts can't fake it.
+pub const OBSERVED_ADDR_THRESHOLD: usize = 3;
+// Queued fragments are paced, a few for each session at a time. The messages not
+// split are sent at once, they do not wait for the fragments.
+pub const DRAIN_FRAGMENTS: Duration = Duration::from_millis(10);
+pub const FRAGMENTS_PER_DRAIN: usize = 4;
+// The fragments queued for a session take at most this many bytes, the messages
+// over it are dropped.
+pub const MAX_FRAGMENT_QUEUE_BYTES: usize = 128 * 1024 * 1024;
+
+// Frames of the fragments queued for a session, shared by the sessions the message is
+// sent to. A frame is flagged if it is the last fragment of the message.
+#[derive(Default)]
+struct FragmentQueue {
+    frames: VecDeque<(Bytes, bool)>,
+    bytes: usize,
+}
+
+impl FragmentQueue {
+    fn pop(&mut self) -> Option<(Bytes, bool)> {
+        let (frame, last) = self.frames.pop_front()?;
+        self.bytes -= frame.len();
+        Some((frame, last))
+    }
+
+    // Drop the fragments left of the message whose fragment is taken last, the peer
+    // can not finish it once a fragment is lost. Return the number of them.
+    fn drop_message(&mut self, last: bool) -> usize {
+        let mut dropped = 0;
+        let mut last = last;
+        while !last {
+            match self.pop() {
+                Some((_, is_last)) => {
+                    dropped += 1;
+                    last = is_last;
+                }
+                None => break,
+            }
+        }
+        dropped
+    }
+}
 
 pub struct NodesManager {
     check_connected_nodes: crossbeam_channel::Receiver<Instant>,
-    known_addrs: FnvHashMap<RawAddr, i32>,
-    connected_addrs: HashMap<SessionId, RawAddr>,
-    max_connects: usize,
+    resolve_peer_hosts: crossbeam_channel::Receiver<Instant>,
+    save_address_book: crossbeam_channel::Receiver<Instant>,
+    export_metrics: crossbeam_channel::Receiver<Instant>,
+    reward_uptime: crossbeam_channel::Receiver<Instant>,
+    drain_fragments: crossbeam_channel::Receiver<Instant>,
+    known_addrs: FnvHashMap<RawAddr, AddrInfo>,
+    // Configured peers, they are never removed because of dial failures or low score.
+    // The addresses resolved from `peer_hosts` are persistent too.
+    persistent_addrs: HashSet<RawAddr>,
+    // Only the configured nodes are on the network.
+    validator_only: bool,
+    // Reserved peers are persistent, and always kept connected. They are dialed first,
+    // do not count toward the session limits, and are never evicted.
+    reserved_addrs: HashSet<RawAddr>,
+    reserved_hosts: HashSet<(String, u16)>,
+    // Public keys of the configured peers, learned from the sessions dialed to them.
+    // Inbound sessions are matched to the configured peers by them.
+    peer_keys: HashMap<RawAddr, String>,
+    dial_scheduler: DialScheduler,
+    max_known_addrs: usize,
+    // Number of new addresses accepted through discovery from the sessions on each IP,
+    // and the start of the window they are counted in.
+    discovered_counts: HashMap<IpAddr, (usize, Instant)>,
+    max_addrs_per_session: usize,
+    // Number of addresses selected in a subnet.
+    max_addrs_per_bucket: usize,
+    address_book_path: Option<PathBuf>,
+    // Peers configured by hostname, and the addresses they were resolved to last time.
+    peer_hosts: HashMap<(String, u16), Vec<RawAddr>>,
+    // Hostnames being resolved, a slow lookup is not started again until it is done.
+    resolving: HashSet<(String, u16)>,
+    resolver: Arc<dyn Resolver>,
+    sessions: SessionRegistry,
+    max_inbound: usize,
+    max_outbound: usize,
+    listen_addrs: Vec<Multiaddr>,
+    advertise_addrs: Vec<Multiaddr>,
+    learn_external_addr: bool,
+    observed_addrs: HashMap<IpAddr, HashMap<SessionId, Bucket>>,
+    // Shared with the service handle, which closes the sessions not allowed right away.
+    allowlist: Arc<RwLock<Allowlist>>,
+    ban_list: BanList,
+    // Scores of the nodes without a known address, such as most inbound peers, by IP,
+    // and when they are updated. Start from `DEFAULT_SCORE`.
+    ip_scores: HashMap<IpAddr, (i32, Instant)>,
+    misbehave_ban_score: i32,
+    misbehave_ban_duration: Duration,
+    metrics: NetworkMetrics,
+    metrics_path: Option<PathBuf>,
+    // Version of the frames sent to other nodes.
+    frame_version: u8,
+    // Codecs to compress the transfer messages with, in the order of preference.
+    compression: Vec<Compression>,
+    compression_threshold: usize,
+    // Add checksums to the frames sent to the peers which check them.
+    enable_checksum: bool,
+    // Messages longer than this are split into fragments, for the peers which can
+    // reassemble them. The frames of the fragments are queued for each session.
+    fragment_size: usize,
+    next_message_id: u32,
+    fragment_queues: HashMap<SessionId, FragmentQueue>,
     nodes_manager_client: NodesManagerClient,
     nodes_manager_service_receiver: crossbeam_channel::Receiver<NodesManagerMessage>,
     service_ctrl: Option<ServiceControl>,
+    shutdown: bool,
 }
 
 impl NodesManager {
-    pub fn new(known_addrs: FnvHashMap<RawAddr, i32>) -> Self {
+    pub fn new(known_addrs: FnvHashMap<RawAddr, AddrInfo>) -> Self {
         let mut node_mgr = NodesManager::default();
         node_mgr.known_addrs = known_addrs;
         node_mgr
     }
 
-    // The [[peers]] 'MUST' be config, Otherwise leads a panic,
-    // and cannot setup network service.
+    // The config should be checked by `NetConfig::validate` first,
+    // invalid peers are ignored here.
     pub fn from_config(cfg: NetConfig) -> Self {
         let mut node_mgr = NodesManager::default();
 
-        let cfg_addrs = cfg
-            .peers
-            .expect("[NodesManager] peers MUST be config in a network config file.");
+        node_mgr.validator_only = cfg.is_validator_only();
+        node_mgr.frame_version = cfg.frame_version();
+        node_mgr.compression = cfg.compression();
+        node_mgr.compression_threshold = cfg.compression_threshold();
+        node_mgr.enable_checksum = cfg.enable_checksum.unwrap_or(false);
+        node_mgr.fragment_size = cfg.fragment_size();
+        node_mgr.max_inbound = cfg.max_inbound();
+        node_mgr.max_outbound = cfg.max_outbound();
+        node_mgr.max_known_addrs = cfg.max_known_addrs.unwrap_or(DEFAULT_MAX_KNOWN_ADDRS);
+        node_mgr.max_addrs_per_session = cfg
+            .max_addrs_per_session
+            .unwrap_or(DEFAULT_MAX_ADDRS_PER_SESSION);
+        node_mgr.max_addrs_per_bucket = cfg
+            .max_addrs_per_bucket
+            .unwrap_or(DEFAULT_MAX_ADDRS_PER_BUCKET);
+        node_mgr.dial_scheduler = DialScheduler::new(
+            cfg.max_dialing.unwrap_or(DEFAULT_MAX_DIALING),
+            cfg.dial_timeout_secs
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_DIAL_TIMEOUT),
+        );
+
+        node_mgr.listen_addrs = parse_multiaddrs(&cfg.listen_addrs());
+        node_mgr.advertise_addrs = parse_multiaddrs(&cfg.advertise_addrs.unwrap_or_default());
+        node_mgr.learn_external_addr = cfg.learn_external_addr();
+        node_mgr.allowlist = Arc::new(RwLock::new(Allowlist::new(
+            cfg.allowed_keys.unwrap_or_default(),
+            cfg.allowed_keys_file.clone(),
+        )));
+        node_mgr.ban_list = BanList::load(cfg.data_dir().join(BAN_LIST_FILE));
+        node_mgr.metrics_path = Some(cfg.data_dir().join(METRICS_FILE));
+        node_mgr.misbehave_ban_score = cfg
+            .misbehave_ban_score
+            .unwrap_or(DEFAULT_MISBEHAVE_BAN_SCORE);
+        node_mgr.misbehave_ban_duration =
+            Duration::from_secs(cfg.misbehave_ban_secs.unwrap_or(DEFAULT_MISBEHAVE_BAN_SECS));
 
-        let max_connects = cfg.max_connects.unwrap_or(DEFAULT_MAX_CONNECTS);
-        node_mgr.max_connects = max_connects;
+        // Restore the addresses learned before restart, then add the configured peers.
+        // Nothing is learned in validator-only mode.
+        if !node_mgr.validator_only {
+            let address_book_path = cfg.data_dir().join(ADDRESS_BOOK_FILE);
+            let mut addrs = load_address_book(&address_book_path);
+            addrs.retain(|(addr, _)| !node_mgr.ban_list.is_ip_banned(&addr.ip()));
+            addrs.sort_by(|(_, a), (_, b)| (b.score, b.last_seen).cmp(&(a.score, a.last_seen)));
+            for (addr, info) in addrs.into_iter().take(node_mgr.max_known_addrs) {
+                node_mgr.known_addrs.insert(RawAddr::from(addr), info);
+            }
+            node_mgr.address_book_path = Some(address_book_path);
+        }
 
-        for addr in cfg_addrs {
-            let ip = addr
-                .ip
-                .expect("[NodeManager] ip 'MUST' be set in known_nodes.");
-            let port = addr
-                .port
-                .expect("[NodeManager] port 'MUST' be set in known_nodes.");
-            let addr_str = format!("{}:{}", ip, port);
-            let socket_addr = SocketAddr::from_str(&addr_str).unwrap();
-            let raw_addr = RawAddr::from(socket_addr);
-            node_mgr.known_addrs.insert(raw_addr, 100);
+        for peer in cfg.peers.unwrap_or_default() {
+            let reserved = peer.is_reserved();
+            match peer.address() {
+                Some(PeerAddress::Ip(socket_addr)) => {
+                    let raw_addr = RawAddr::from(socket_addr);
+                    node_mgr
+                        .known_addrs
+                        .entry(raw_addr.clone())
+                        .or_insert_with(AddrInfo::default);
+                    if reserved {
+                        node_mgr.reserved_addrs.insert(raw_addr.clone());
+                    }
+                    node_mgr.persistent_addrs.insert(raw_addr);
+                }
+                Some(PeerAddress::Host(host, port)) => {
+                    if reserved {
+                        node_mgr.reserved_hosts.insert((host.clone(), port));
+                    }
+                    node_mgr.peer_hosts.insert((host, port), Vec::new());
+                }
+                None => warn!("[NodesManager] Ignore invalid peer {:?}", peer),
+            }
         }
 
         node_mgr
     }
 
+    // Handle the messages until a `ShutdownReq` is handled.
     pub fn run(&mut self) {
-        loop {
+        self.resolve_peer_hosts();
+
+        while !self.shutdown {
             select! {
                 recv(self.nodes_manager_service_receiver) -> msg => {
                     match msg {
@@ -76,8 +279,30 @@ impl NodesManager {
                     }
                 }
                 recv(self.check_connected_nodes) -> _ => {
+                    self.allowlist.write().unwrap().reload_if_changed();
+                    self.ban_list.remove_expired();
+                    self.prune_ip_scores(Instant::now());
+                    self.prune_discovered_counts(Instant::now());
                     self.dial_nodes();
                 }
+                recv(self.resolve_peer_hosts) -> _ => {
+                    self.resolve_peer_hosts();
+                }
+                recv(self.save_address_book) -> _ => {
+                    self.save_address_book();
+                }
+                recv(self.export_metrics) -> _ => {
+                    self.save_metrics();
+                }
+                recv(self.reward_uptime) -> _ => {
+                    let addrs: Vec<SocketAddr> = self.sessions.dialed_addrs().collect();
+                    for addr in addrs {
+                        self.update_score(&RawAddr::from(addr), ScoreEvent::SessionUptime);
+                    }
+                }
+                recv(self.drain_fragments) -> _ => {
+                    self.drain_fragments();
+                }
             }
         }
     }
@@ -86,41 +311,700 @@ impl NodesManager {
         self.nodes_manager_client.clone()
     }
 
+    pub fn allowlist(&self) -> Arc<RwLock<Allowlist>> {
+        Arc::clone(&self.allowlist)
+    }
+
     pub fn dial_nodes(&mut self) {
         debug!("=============================");
         for raw_addr in self.known_addrs.keys() {
             debug!("Node in known: {:?}", raw_addr.socket_addr());
         }
         debug!("-----------------------------");
-        for raw_addr in self.connected_addrs.values() {
-            debug!("Node in connected: {:?}", raw_addr.socket_addr());
+        for session in self.sessions.iter() {
+            debug!(
+                "Node in connected: {:?}, type: {:?}",
+                session.addr, session.ty
+            );
         }
         debug!("=============================");
 
-        if self.connected_addrs.len() < self.max_connects {
-            for key in self.known_addrs.keys() {
-                if !self.connected_addrs.values().any(|value| *value == *key) {
-                    debug!("[dial_nodes] Connect to {:?}", key.socket_addr());
-
-                    if let Some(ref mut ctrl) = self.service_ctrl {
-                        match ctrl.dial(key.socket_addr().to_multiaddr().unwrap()) {
-                            Ok(_) => {
-                                debug!("[dial_nodes] Dail success");
-                            }
-                            Err(err) => {
-                                warn!("[dial_nodes] Dail failed : {:?}", err);
-                            }
-                        }
+        let now = Instant::now();
+        for addr in self.dial_scheduler.take_timed_out(now) {
+            warn!("[dial_nodes] Dial {:?} timeout", addr);
+            self.dial_failed(addr);
+        }
+
+        self.dial_reserved(now);
+
+        let dialing = self
+            .dial_scheduler
+            .dialing()
+            .filter(|addr| !self.is_reserved(&RawAddr::from(**addr)))
+            .count();
+        let slots = self
+            .max_outbound
+            .saturating_sub(self.session_count(false) + dialing)
+            .min(self.dial_scheduler.free_slots());
+        if slots == 0 {
+            return;
+        }
+
+        let mut persistent = Vec::new();
+        let mut discovered = Vec::new();
+        for (key, info) in self
+            .known_addrs
+            .iter()
+            .filter(|(key, _)| !self.is_reserved(key))
+            .filter(|(key, _)| !self.ban_list.is_ip_banned(&key.socket_addr().ip()))
+            .filter(|(key, _)| !self.sessions.is_dialed(&key.socket_addr()))
+            .filter(|(key, _)| self.dial_scheduler.can_dial(&key.socket_addr(), now))
+        {
+            if self.is_persistent(key) {
+                persistent.push((key.socket_addr(), info.score));
+            } else {
+                discovered.push((key.socket_addr(), info.score));
+            }
+        }
+
+        // Dial the persistent nodes first, then select from the discovered nodes randomly,
+        // weighted by score and limited per subnet.
+        persistent.sort_by(|a, b| b.1.cmp(&a.1));
+        let mut targets: Vec<SocketAddr> = persistent
+            .into_iter()
+            .take(slots)
+            .map(|(addr, _)| addr)
+            .collect();
+        if targets.len() < slots {
+            let occupied: Vec<IpAddr> = self
+                .sessions
+                .dialed_addrs()
+                .chain(self.dial_scheduler.dialing().cloned())
+                .chain(targets.iter().cloned())
+                .map(|addr| addr.ip())
+                .collect();
+            let selected = select_addrs(
+                &discovered,
+                slots - targets.len(),
+                self.max_addrs_per_bucket,
+                &occupied,
+                &mut thread_rng(),
+            );
+            targets.extend(selected);
+        }
+
+        self.dial(targets, now);
+    }
+
+    // Dial the reserved nodes not connected, they do not take the dial slots.
+    fn dial_reserved(&mut self, now: Instant) {
+        let targets: Vec<SocketAddr> = self
+            .reserved_addrs()
+            .filter(|addr| !self.is_peer_connected(addr))
+            .map(RawAddr::socket_addr)
+            .filter(|addr| !self.ban_list.is_ip_banned(&addr.ip()))
+            .filter(|addr| self.dial_scheduler.can_dial(addr, now))
+            .collect();
+        self.dial(targets, now);
+    }
+
+    fn dial(&mut self, targets: Vec<SocketAddr>, now: Instant) {
+        for addr in targets {
+            debug!("[dial_nodes] Connect to {:?}", addr);
+
+            if let Some(ref mut ctrl) = self.service_ctrl {
+                match ctrl.dial(addr.to_multiaddr().unwrap()) {
+                    Ok(_) => {
+                        debug!("[dial_nodes] Dail success");
+                        self.dial_scheduler.start(addr, now);
+                    }
+                    Err(err) => {
+                        warn!("[dial_nodes] Dail failed : {:?}", err);
+                    }
+                }
+            }
+        }
+    }
+
+    // Persistent nodes are retried forever with backoff, the discovered nodes are
+    // removed once the retry budget is used up.
+    fn dial_failed(&mut self, addr: SocketAddr) {
+        let raw_addr = RawAddr::from(addr);
+        self.update_score(&raw_addr, ScoreEvent::DialFailure);
+
+        let failure_count = match self.known_addrs.get(&raw_addr) {
+            Some(info) => info.failure_count,
+            None => {
+                self.dial_scheduler.remove(&addr);
+                return;
+            }
+        };
+        if !self.is_persistent(&raw_addr) && failure_count >= DIAL_RETRY_BUDGET {
+            info!(
+                "[dial_failed] Remove {:?} from known nodes, failed {} times",
+                addr, failure_count
+            );
+            self.remove_addr(&raw_addr);
+            return;
+        }
+
+        let backoff = self
+            .dial_scheduler
+            .fail(addr, failure_count, Instant::now());
+        debug!("[dial_failed] Dial {:?} again after {:?}", addr, backoff);
+    }
+
+    pub fn is_persistent(&self, addr: &RawAddr) -> bool {
+        self.persistent_addrs.contains(addr)
+            || self.peer_hosts.values().any(|addrs| addrs.contains(addr))
+    }
+
+    pub fn is_reserved(&self, addr: &RawAddr) -> bool {
+        self.reserved_addrs.contains(addr)
+            || self
+                .reserved_hosts
+                .iter()
+                .filter_map(|host| self.peer_hosts.get(host))
+                .any(|addrs| addrs.contains(addr))
+    }
+
+    fn reserved_addrs(&self) -> impl Iterator<Item = &RawAddr> {
+        self.reserved_addrs.iter().chain(
+            self.reserved_hosts
+                .iter()
+                .filter_map(move |host| self.peer_hosts.get(host))
+                .flatten(),
+        )
+    }
+
+    // The configured peer of a session: the dialed address if it is configured, or the
+    // configured address whose public key matches. The IP alone is not enough, any node
+    // behind the same host or NAT shares it.
+    fn configured_peer(
+        &self,
+        dialed_addr: Option<&SocketAddr>,
+        public_key: Option<&str>,
+    ) -> Option<RawAddr> {
+        if let Some(addr) = dialed_addr {
+            let raw_addr = RawAddr::from(*addr);
+            if self.is_persistent(&raw_addr) {
+                return Some(raw_addr);
+            }
+        }
+        let public_key = public_key?;
+        self.peer_keys
+            .iter()
+            .find(|(addr, key)| key.eq_ignore_ascii_case(public_key) && self.is_persistent(addr))
+            .map(|(addr, _)| addr.clone())
+    }
+
+    fn session_peer(&self, session: &SessionInfo) -> Option<RawAddr> {
+        self.configured_peer(
+            session.dialed_addr.as_ref(),
+            session.public_key.as_ref().map(String::as_str),
+        )
+    }
+
+    // Whether a session with the configured peer is open, dialed by either side.
+    fn is_peer_connected(&self, addr: &RawAddr) -> bool {
+        self.sessions
+            .iter()
+            .any(|session| self.session_peer(session).as_ref() == Some(addr))
+    }
+
+    fn is_reserved_session(&self, session: &SessionInfo) -> bool {
+        self.session_peer(session)
+            .map_or(false, |addr| self.is_reserved(&addr))
+    }
+
+    // Remember the public key of a configured peer dialed by us.
+    fn learn_peer_key(&mut self, dialed_addr: &SocketAddr, public_key: Option<&String>) {
+        let raw_addr = RawAddr::from(*dialed_addr);
+        if let Some(key) = public_key {
+            if self.is_persistent(&raw_addr) {
+                self.peer_keys.insert(raw_addr, key.to_lowercase());
+            }
+        }
+    }
+
+    // Number of sessions in the direction, the reserved ones are not counted.
+    fn session_count(&self, inbound: bool) -> usize {
+        self.sessions
+            .directed(inbound)
+            .filter(|session| !self.is_reserved_session(session))
+            .count()
+    }
+
+    // In validator-only mode, accept the sessions with the configured peers, or with
+    // a public key in the allowed keys.
+    fn is_configured_session(
+        &self,
+        dialed_addr: Option<&SocketAddr>,
+        public_key: Option<&str>,
+    ) -> bool {
+        !self.validator_only || self.configured_peer(dialed_addr, public_key).is_some() || {
+            let allowlist = self.allowlist.read().unwrap();
+            allowlist.is_enabled() && allowlist.is_allowed(public_key)
+        }
+    }
+
+    // Score of the node on the IP: the best score of its known addresses, or the score
+    // of the IP if no address is known.
+    fn session_score(&self, ip: &IpAddr) -> i32 {
+        self.known_addrs
+            .iter()
+            .filter(|(addr, _)| addr.socket_addr().ip() == *ip)
+            .map(|(_, info)| info.score)
+            .max()
+            .or_else(|| self.ip_scores.get(ip).map(|(score, _)| *score))
+            .unwrap_or(DEFAULT_SCORE)
+    }
+
+    // The session with the lowest score not with a configured peer, and the score.
+    // The sessions with the configured peers are protected.
+    fn lowest_session(&self, inbound: bool) -> Option<(SessionId, i32)> {
+        self.sessions
+            .directed(inbound)
+            .filter(|session| self.session_peer(session).is_none())
+            .map(|session| (session.id, self.session_score(&session.addr.ip())))
+            .min_by_key(|(_, score)| *score)
+    }
+
+    fn evict_session(&mut self, session_id: SessionId) {
+        self.disconnect(session_id);
+        self.sessions.remove(session_id);
+    }
+
+    // Make room for a new session if the inbound or outbound sessions are full, by evicting
+    // the unprotected session with the lowest score. If no session has a lower score than
+    // the new one, return false to reject the new one. A session with a configured peer
+    // evicts any unprotected session, but is rejected too if there is none. A reserved peer
+    // takes no room for one session.
+    fn admit_session(&mut self, session: &SessionInfo) -> bool {
+        let inbound = session.is_inbound();
+        let peer = self.session_peer(session);
+        if let Some(ref peer) = peer {
+            if self.is_reserved(peer) {
+                return !self.is_peer_connected(peer);
+            }
+        }
+
+        let limit = if inbound {
+            self.max_inbound
+        } else {
+            self.max_outbound
+        };
+        if self.session_count(inbound) < limit {
+            return true;
+        }
+
+        let protected = peer.is_some();
+        let score = self.session_score(&session.addr.ip());
+        match self.lowest_session(inbound) {
+            Some((session_id, lowest_score)) if protected || lowest_score < score => {
+                info!(
+                    "[admit_session] Sessions are full, evict session {} with score {} for {:?}",
+                    session_id, lowest_score, session.addr
+                );
+                self.evict_session(session_id);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Disconnect the sessions with the lowest score until the limits are met.
+    fn enforce_session_limits(&mut self) {
+        for &(inbound, limit) in [(true, self.max_inbound), (false, self.max_outbound)].iter() {
+            while self.session_count(inbound) > limit {
+                match self.lowest_session(inbound) {
+                    Some((session_id, _)) => {
+                        info!(
+                            "[enforce_session_limits] Too many sessions, disconnect session {}",
+                            session_id
+                        );
+                        self.evict_session(session_id);
                     }
-                    break;
+                    None => break,
                 }
             }
         }
     }
 
+    // Apply a score event to a known address, and evict it if the score is too low.
+    // Persistent addresses are never evicted. Return the new score if the address is known.
+    pub fn update_score(&mut self, addr: &RawAddr, event: ScoreEvent) -> Option<i32> {
+        let persistent = self.is_persistent(addr);
+        let (score, evict) = match self.known_addrs.get_mut(addr) {
+            Some(info) => {
+                let score = peer_score::update_score(info, event);
+                debug!(
+                    "[update_score] {:?} of {:?}, score: {}",
+                    event,
+                    addr.socket_addr(),
+                    score
+                );
+                (score, !persistent && peer_score::should_evict(info))
+            }
+            None => return None,
+        };
+
+        if evict {
+            info!(
+                "[update_score] Evict {:?} from known nodes, score too low",
+                addr.socket_addr()
+            );
+            self.remove_addr(addr);
+        }
+        Some(score)
+    }
+
+    // Apply the event to the addresses known on the IP, or to the score of the IP if none
+    // is known, which is the case for most inbound peers. Return the lowest new score.
+    pub fn score_ip(&mut self, ip: &IpAddr, event: ScoreEvent) -> i32 {
+        let addrs: Vec<RawAddr> = self
+            .known_addrs
+            .keys()
+            .filter(|addr| addr.socket_addr().ip() == *ip)
+            .cloned()
+            .collect();
+        if addrs.is_empty() {
+            let (score, updated) = self
+                .ip_scores
+                .entry(*ip)
+                .or_insert((DEFAULT_SCORE, Instant::now()));
+            *score = (*score + event.delta()).max(MIN_SCORE).min(MAX_SCORE);
+            *updated = Instant::now();
+            debug!("[score_ip] {:?} of {:?}, score: {}", event, ip, score);
+            return *score;
+        }
+        addrs
+            .iter()
+            .filter_map(|addr| self.update_score(addr, event))
+            .min()
+            .unwrap_or(DEFAULT_SCORE)
+    }
+
+    // Forget the penalties of the node on the IP.
+    fn reset_ip_score(&mut self, ip: &IpAddr) {
+        self.ip_scores.remove(ip);
+        for (addr, info) in self.known_addrs.iter_mut() {
+            if addr.socket_addr().ip() == *ip {
+                info.score = DEFAULT_SCORE;
+            }
+        }
+    }
+
+    // Forget the scores of the IPs not updated for `IP_SCORE_TTL` and without a session.
+    fn prune_ip_scores(&mut self, now: Instant) {
+        let sessions = &self.sessions;
+        self.ip_scores.retain(|ip, (_, updated)| {
+            now.duration_since(*updated) < IP_SCORE_TTL
+                || sessions.iter().any(|session| session.addr.ip() == *ip)
+        });
+    }
+
+    // Forget the discovery counts of the windows passed.
+    fn prune_discovered_counts(&mut self, now: Instant) {
+        self.discovered_counts
+            .retain(|_, (_, start)| now.duration_since(*start) < DISCOVERED_ADDRS_WINDOW);
+    }
+
+    // Make room for a new address if the address book is full, by evicting the entry
+    // with the lowest score and seen longest ago. Persistent and connected entries are
+    // kept. Return false if no entry has a lower score than a new one, unless the new one
+    // is persistent.
+    fn make_room(&mut self, persistent: bool) -> bool {
+        if self.known_addrs.len() < self.max_known_addrs {
+            return true;
+        }
+
+        let worst = self
+            .known_addrs
+            .iter()
+            .filter(|(addr, _)| !self.is_persistent(addr))
+            .filter(|(addr, _)| !self.sessions.is_dialed(&addr.socket_addr()))
+            .min_by_key(|(_, info)| (info.score, info.last_seen))
+            .map(|(addr, info)| (addr.clone(), info.score));
+
+        match worst {
+            Some((addr, score)) if persistent || score < DEFAULT_SCORE => {
+                debug!(
+                    "[make_room] Address book is full, evict {:?}",
+                    addr.socket_addr()
+                );
+                self.remove_addr(&addr);
+                true
+            }
+            _ => persistent,
+        }
+    }
+
+    fn remove_addr(&mut self, addr: &RawAddr) {
+        self.known_addrs.remove(addr);
+        self.dial_scheduler.remove(&addr.socket_addr());
+    }
+
     pub fn set_service_task_sender(&mut self, ctrl: ServiceControl) {
         self.service_ctrl = Some(ctrl);
     }
+
+    // Send the message to the sessions on the transfer protocol, encoded once for the
+    // sessions with the same options negotiated. A long message is split into fragments
+    // for the sessions which can reassemble them, and the fragments are queued.
+    fn send_transfer(&mut self, session_ids: Vec<SessionId>, key: String, body: Vec<u8>) {
+        let mut groups: HashMap<(Option<Compression>, bool, bool), Vec<SessionId>> = HashMap::new();
+        for session_id in session_ids {
+            let options = self
+                .sessions
+                .get(session_id)
+                .map_or((None, false, false), |session| {
+                    (session.compression, session.checksum, session.fragment)
+                });
+            groups
+                .entry(options)
+                .or_insert_with(Vec::new)
+                .push(session_id);
+        }
+
+        for ((compression, checksum, fragment), session_ids) in groups {
+            if fragment && body.len() > self.fragment_size {
+                let (mut flags, body) =
+                    compress_body(compression, self.compression_threshold, body.clone());
+                if checksum {
+                    flags |= FLAG_CHECKSUM;
+                }
+                let msg_id = self.next_message_id;
+                self.next_message_id = self.next_message_id.wrapping_add(1);
+                let frames: Vec<Bytes> =
+                    encode_fragments(flags, msg_id, &key, &body, self.fragment_size)
+                        .into_iter()
+                        .map(Bytes::from)
+                        .collect();
+                if frames.is_empty() {
+                    continue;
+                }
+                let bytes: usize = frames.iter().map(Bytes::len).sum();
+                let last = frames.len() - 1;
+                for session_id in session_ids {
+                    let queue = self
+                        .fragment_queues
+                        .entry(session_id)
+                        .or_insert_with(FragmentQueue::default);
+                    if queue.bytes + bytes > MAX_FRAGMENT_QUEUE_BYTES {
+                        warn!(
+                            "Drop a message with key {} to session [{}], {} bytes of fragments are queued",
+                            key, session_id, queue.bytes
+                        );
+                        continue;
+                    }
+                    queue.frames.extend(
+                        frames
+                            .iter()
+                            .enumerate()
+                            .map(|(index, frame)| (frame.clone(), index == last)),
+                    );
+                    queue.bytes += bytes;
+                }
+                self.fragment_queues
+                    .retain(|_, queue| !queue.frames.is_empty());
+                continue;
+            }
+
+            let mut buf = BytesMut::with_capacity(4 + 4 + 1 + key.len() + body.len());
+            encode_message(
+                &mut buf,
+                self.frame_version,
+                compression,
+                self.compression_threshold,
+                checksum,
+                key.clone(),
+                body.clone(),
+            );
+            if let Some(ref mut ctrl) = self.service_ctrl {
+                //FIXME: handle the error!
+                if ctrl
+                    .send_message(
+                        Some(session_ids.clone()),
+                        TRANSFER_PROTOCOL_ID,
+                        buf.to_vec(),
+                    )
+                    .is_ok()
+                {
+                    for session_id in session_ids {
+                        self.sessions.record_sent(session_id, buf.len(), 1);
+                    }
+                }
+            }
+        }
+    }
+
+    // Send the next few queued fragments of each session.
+    fn drain_fragments(&mut self) {
+        let ctrl = match self.service_ctrl {
+            Some(ref mut ctrl) => ctrl,
+            None => return,
+        };
+        for (session_id, queue) in self.fragment_queues.iter_mut() {
+            for _ in 0..FRAGMENTS_PER_DRAIN {
+                let (frame, last) = match queue.pop() {
+                    Some(entry) => entry,
+                    None => break,
+                };
+                match ctrl.send_message(
+                    Some(vec![*session_id]),
+                    TRANSFER_PROTOCOL_ID,
+                    frame.to_vec(),
+                ) {
+                    Ok(_) => {
+                        self.sessions
+                            .record_sent(*session_id, frame.len(), last as usize);
+                    }
+                    Err(err) => {
+                        let dropped = queue.drop_message(last);
+                        warn!(
+                            "Send a fragment to session [{}] failed: {:?}, drop the other {} fragments of the message",
+                            session_id, err, dropped
+                        );
+                    }
+                }
+            }
+        }
+        self.fragment_queues
+            .retain(|_, queue| !queue.frames.is_empty());
+    }
+
+    pub fn save_address_book(&self) {
+        if let Some(ref path) = self.address_book_path {
+            let addrs = self
+                .known_addrs
+                .iter()
+                .map(|(addr, info)| (addr.socket_addr(), info));
+            match save_address_book(path, addrs) {
+                Ok(()) => debug!(
+                    "[save_address_book] Save {} addresses to {:?}",
+                    self.known_addrs.len(),
+                    path
+                ),
+                Err(err) => warn!("[save_address_book] Save to {:?} failed : {:?}", path, err),
+            }
+        }
+    }
+
+    pub fn save_metrics(&self) {
+        if let Some(ref path) = self.metrics_path {
+            if let Err(err) = save_metrics(path, &self.metrics) {
+                warn!("[save_metrics] Save to {:?} failed : {:?}", path, err);
+            }
+        }
+    }
+
+    pub fn disconnect(&mut self, session_id: SessionId) {
+        if let Some(ref mut ctrl) = self.service_ctrl {
+            if let Err(err) = ctrl.disconnect(session_id) {
+                warn!(
+                    "[disconnect] Disconnect session {} failed : {:?}",
+                    session_id, err
+                );
+            }
+        }
+    }
+
+    // Disconnect the sessions dialed to the address, and the ones opened by the node with
+    // its public key unless they are still with a configured peer. Forget them and the key.
+    fn evict_sessions_to(&mut self, addr: &SocketAddr) {
+        let key = self.peer_keys.remove(&RawAddr::from(*addr));
+        let is_peer_key = |session: &SessionInfo| match (&key, &session.public_key) {
+            (Some(key), Some(session_key)) => key.eq_ignore_ascii_case(session_key),
+            _ => false,
+        };
+        let session_ids: Vec<SessionId> = self
+            .sessions
+            .iter()
+            .filter(|session| {
+                session.dialed_addr == Some(*addr)
+                    || (is_peer_key(session) && self.session_peer(session).is_none())
+            })
+            .map(|session| session.id)
+            .collect();
+        for session_id in session_ids {
+            debug!(
+                "[evict_sessions_to] Disconnect session {} to {:?}",
+                session_id, addr
+            );
+            self.evict_session(session_id);
+        }
+    }
+
+    pub fn set_resolver(&mut self, resolver: Arc<dyn Resolver>) {
+        self.resolver = resolver;
+    }
+
+    // Resolve the peer hostnames again. The lookups block, so they run on their own
+    // threads and the results come back as `PeerHostResolvedReq`.
+    pub fn resolve_peer_hosts(&mut self) {
+        let hosts: Vec<(String, u16)> = self.peer_hosts.keys().cloned().collect();
+        for (host, port) in hosts {
+            self.resolve_peer_host(host, port);
+        }
+    }
+
+    fn resolve_peer_host(&mut self, host: String, port: u16) {
+        if !self.resolving.insert((host.clone(), port)) {
+            return;
+        }
+
+        let resolver = Arc::clone(&self.resolver);
+        let client = self.client();
+        thread::spawn(move || {
+            let result = resolver.resolve(&host, port);
+            client.peer_host_resolved(PeerHostResolvedReq::new(host, port, result));
+        });
+    }
+
+    // Configured advertise addresses, and the external addresses learned from peers
+    // if enabled. An empty list means advertising the listen addresses.
+    pub fn advertise_addrs(&self) -> Vec<Multiaddr> {
+        let mut addrs = self.advertise_addrs.clone();
+
+        if self.learn_external_addr {
+            let ports: Vec<u16> = self
+                .listen_addrs
+                .iter()
+                .filter_map(|addr| multiaddr_to_socketaddr(addr))
+                .map(|addr| addr.port())
+                .collect();
+
+            for (ip, sessions) in &self.observed_addrs {
+                let buckets: HashSet<&Bucket> = sessions.values().collect();
+                if buckets.len() < OBSERVED_ADDR_THRESHOLD {
+                    continue;
+                }
+                for port in &ports {
+                    if let Ok(addr) = SocketAddr::new(*ip, *port).to_multiaddr() {
+                        if !addrs.contains(&addr) {
+                            addrs.push(addr);
+                        }
+                    }
+                }
+            }
+        }
+
+        addrs
+    }
+}
+
+fn parse_multiaddrs(addrs: &[String]) -> Vec<Multiaddr> {
+    addrs
+        .iter()
+        .filter_map(|addr| match addr.parse::<Multiaddr>() {
+            Ok(multiaddr) => Some(multiaddr),
+            Err(err) => {
+                warn!("[NodesManager] Invalid address {:?}: {:?}", addr, err);
+                None
+            }
+        })
+        .collect()
 }
 
 impl Default for NodesManager {
@@ -131,12 +1015,51 @@ impl Default for NodesManager {
 
         NodesManager {
             check_connected_nodes: ticker,
+            resolve_peer_hosts: tick(RESOLVE_PEER_HOSTS),
+            save_address_book: tick(SAVE_ADDRESS_BOOK),
+            export_metrics: tick(EXPORT_METRICS),
+            reward_uptime: tick(SESSION_UPTIME_INTERVAL),
+            drain_fragments: tick(DRAIN_FRAGMENTS),
             known_addrs: FnvHashMap::default(),
-            connected_addrs: HashMap::default(),
-            max_connects: DEFAULT_MAX_CONNECTS,
+            persistent_addrs: HashSet::default(),
+            validator_only: false,
+            reserved_addrs: HashSet::default(),
+            reserved_hosts: HashSet::default(),
+            peer_keys: HashMap::default(),
+            dial_scheduler: DialScheduler::default(),
+            max_known_addrs: DEFAULT_MAX_KNOWN_ADDRS,
+            discovered_counts: HashMap::default(),
+            max_addrs_per_session: DEFAULT_MAX_ADDRS_PER_SESSION,
+            max_addrs_per_bucket: DEFAULT_MAX_ADDRS_PER_BUCKET,
+            address_book_path: None,
+            peer_hosts: HashMap::default(),
+            resolving: HashSet::default(),
+            resolver: Arc::new(SystemResolver),
+            sessions: SessionRegistry::default(),
+            max_inbound: DEFAULT_MAX_INBOUND,
+            max_outbound: DEFAULT_MAX_CONNECTS,
+            listen_addrs: Vec::default(),
+            advertise_addrs: Vec::default(),
+            learn_external_addr: false,
+            observed_addrs: HashMap::default(),
+            allowlist: Arc::new(RwLock::new(Allowlist::default())),
+            ban_list: BanList::default(),
+            ip_scores: HashMap::default(),
+            misbehave_ban_score: DEFAULT_MISBEHAVE_BAN_SCORE,
+            misbehave_ban_duration: Duration::from_secs(DEFAULT_MISBEHAVE_BAN_SECS),
+            metrics: NetworkMetrics::default(),
+            metrics_path: None,
+            frame_version: FRAME_VERSION_LEGACY,
+            compression: Compression::all(),
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            enable_checksum: false,
+            fragment_size: DEFAULT_FRAGMENT_SIZE,
+            next_message_id: 0,
+            fragment_queues: HashMap::default(),
             nodes_manager_client: client,
             nodes_manager_service_receiver: rx,
             service_ctrl: None,
+            shutdown: false,
         }
     }
 }
@@ -159,18 +1082,66 @@ impl NodesManagerClient {
         self.send_req(NodesManagerMessage::DelNodeReq(req));
     }
 
+    pub fn add_peer_host(&self, req: AddPeerHostReq) {
+        self.send_req(NodesManagerMessage::AddPeerHostReq(req));
+    }
+
+    pub fn del_peer_host(&self, req: DelPeerHostReq) {
+        self.send_req(NodesManagerMessage::DelPeerHostReq(req));
+    }
+
+    pub fn peer_host_resolved(&self, req: PeerHostResolvedReq) {
+        self.send_req(NodesManagerMessage::PeerHostResolvedReq(req));
+    }
+
     pub fn get_random_nodes(&self, req: GetRandomNodesReq) {
         self.send_req(NodesManagerMessage::GetRandomNodesReq(req));
     }
 
+    pub fn session_open(&self, req: SessionOpenReq) {
+        self.send_req(NodesManagerMessage::SessionOpen(req));
+    }
+
     pub fn add_connected_node(&self, req: AddConnectedNodeReq) {
         self.send_req(NodesManagerMessage::AddConnectedNodeReq(req));
     }
 
+    pub fn dial_failed(&self, req: DialFailedReq) {
+        self.send_req(NodesManagerMessage::DialFailed(req));
+    }
+
+    pub fn misbehave(&self, req: MisbehaveReq) {
+        self.send_req(NodesManagerMessage::Misbehave(req));
+    }
+
+    pub fn update_score(&self, req: UpdateScoreReq) {
+        self.send_req(NodesManagerMessage::UpdateScore(req));
+    }
+
     pub fn del_connected_node(&self, req: DelConnectedNodeReq) {
         self.send_req(NodesManagerMessage::DelConnectedNodeReq(req));
     }
 
+    pub fn protocol_open(&self, req: ProtocolOpenReq) {
+        self.send_req(NodesManagerMessage::ProtocolOpen(req));
+    }
+
+    pub fn protocol_close(&self, req: ProtocolCloseReq) {
+        self.send_req(NodesManagerMessage::ProtocolClose(req));
+    }
+
+    pub fn message_received(&self, req: MessageReceivedReq) {
+        self.send_req(NodesManagerMessage::MessageReceived(req));
+    }
+
+    pub fn peer_capabilities(&self, req: PeerCapabilitiesReq) {
+        self.send_req(NodesManagerMessage::PeerCapabilities(req));
+    }
+
+    pub fn get_sessions(&self, req: GetSessionsReq) {
+        self.send_req(NodesManagerMessage::GetSessions(req));
+    }
+
     pub fn broadcast(&self, req: BroadcastReq) {
         self.send_req(NodesManagerMessage::Broadcast(req));
     }
@@ -183,6 +1154,38 @@ impl NodesManagerClient {
         self.send_req(NodesManagerMessage::GetPeerCount(req));
     }
 
+    pub fn set_max_connects(&self, req: SetMaxConnectsReq) {
+        self.send_req(NodesManagerMessage::SetMaxConnects(req));
+    }
+
+    pub fn ban(&self, req: BanReq) {
+        self.send_req(NodesManagerMessage::Ban(req));
+    }
+
+    pub fn unban(&self, req: UnbanReq) {
+        self.send_req(NodesManagerMessage::Unban(req));
+    }
+
+    pub fn set_allowed_keys(&self, req: SetAllowedKeysReq) {
+        self.send_req(NodesManagerMessage::SetAllowedKeys(req));
+    }
+
+    pub fn shutdown(&self, req: ShutdownReq) {
+        self.send_req(NodesManagerMessage::Shutdown(req));
+    }
+
+    pub fn get_metrics(&self, req: GetMetricsReq) {
+        self.send_req(NodesManagerMessage::GetMetrics(req));
+    }
+
+    pub fn get_advertise_addrs(&self, req: GetAdvertiseAddrsReq) {
+        self.send_req(NodesManagerMessage::GetAdvertiseAddrs(req));
+    }
+
+    pub fn add_observed_addr(&self, req: AddObservedAddrReq) {
+        self.send_req(NodesManagerMessage::AddObservedAddr(req));
+    }
+
     fn send_req(&self, req: NodesManagerMessage) {
         match self.sender.try_send(req) {
             Ok(_) => {
@@ -199,12 +1202,32 @@ impl NodesManagerClient {
 pub enum NodesManagerMessage {
     AddNodeReq(AddNodeReq),
     DelNodeReq(DelNodeReq),
+    AddPeerHostReq(AddPeerHostReq),
+    DelPeerHostReq(DelPeerHostReq),
+    PeerHostResolvedReq(PeerHostResolvedReq),
     GetRandomNodesReq(GetRandomNodesReq),
+    SessionOpen(SessionOpenReq),
     AddConnectedNodeReq(AddConnectedNodeReq),
     DelConnectedNodeReq(DelConnectedNodeReq),
+    ProtocolOpen(ProtocolOpenReq),
+    ProtocolClose(ProtocolCloseReq),
+    MessageReceived(MessageReceivedReq),
+    PeerCapabilities(PeerCapabilitiesReq),
+    GetSessions(GetSessionsReq),
+    DialFailed(DialFailedReq),
+    UpdateScore(UpdateScoreReq),
+    Misbehave(MisbehaveReq),
     Broadcast(BroadcastReq),
     SingleTxReq(SingleTxReq),
     GetPeerCount(GetPeerCountReq),
+    SetMaxConnects(SetMaxConnectsReq),
+    SetAllowedKeys(SetAllowedKeysReq),
+    Ban(BanReq),
+    Unban(UnbanReq),
+    GetMetrics(GetMetricsReq),
+    Shutdown(ShutdownReq),
+    GetAdvertiseAddrs(GetAdvertiseAddrsReq),
+    AddObservedAddr(AddObservedAddrReq),
 }
 
 impl NodesManagerMessage {
@@ -212,46 +1235,286 @@ impl NodesManagerMessage {
         match self {
             NodesManagerMessage::AddNodeReq(req) => req.handle(service),
             NodesManagerMessage::DelNodeReq(req) => req.handle(service),
+            NodesManagerMessage::AddPeerHostReq(req) => req.handle(service),
+            NodesManagerMessage::DelPeerHostReq(req) => req.handle(service),
+            NodesManagerMessage::PeerHostResolvedReq(req) => req.handle(service),
             NodesManagerMessage::GetRandomNodesReq(req) => req.handle(service),
+            NodesManagerMessage::SessionOpen(req) => req.handle(service),
             NodesManagerMessage::AddConnectedNodeReq(req) => req.handle(service),
             NodesManagerMessage::DelConnectedNodeReq(req) => req.handle(service),
+            NodesManagerMessage::ProtocolOpen(req) => req.handle(service),
+            NodesManagerMessage::ProtocolClose(req) => req.handle(service),
+            NodesManagerMessage::MessageReceived(req) => req.handle(service),
+            NodesManagerMessage::PeerCapabilities(req) => req.handle(service),
+            NodesManagerMessage::GetSessions(req) => req.handle(service),
+            NodesManagerMessage::DialFailed(req) => req.handle(service),
+            NodesManagerMessage::UpdateScore(req) => req.handle(service),
+            NodesManagerMessage::Misbehave(req) => req.handle(service),
             NodesManagerMessage::Broadcast(req) => req.handle(service),
             NodesManagerMessage::SingleTxReq(req) => req.handle(service),
             NodesManagerMessage::GetPeerCount(req) => req.handle(service),
+            NodesManagerMessage::SetMaxConnects(req) => req.handle(service),
+            NodesManagerMessage::SetAllowedKeys(req) => req.handle(service),
+            NodesManagerMessage::Ban(req) => req.handle(service),
+            NodesManagerMessage::Unban(req) => req.handle(service),
+            NodesManagerMessage::GetMetrics(req) => req.handle(service),
+            NodesManagerMessage::Shutdown(req) => req.handle(service),
+            NodesManagerMessage::GetAdvertiseAddrs(req) => req.handle(service),
+            NodesManagerMessage::AddObservedAddr(req) => req.handle(service),
         }
     }
 }
 
 pub struct AddNodeReq {
     addr: SocketAddr,
+    persistent: bool,
+    reserved: bool,
+    // The session the address is learned from.
+    source: Option<SessionId>,
 }
 
 impl AddNodeReq {
     pub fn new(addr: SocketAddr) -> Self {
-        AddNodeReq { addr }
+        AddNodeReq {
+            addr,
+            persistent: false,
+            reserved: false,
+            source: None,
+        }
     }
 
-    pub fn handle(self, service: &mut NodesManager) {
-        service
-            .known_addrs
-            .entry(RawAddr::from(self.addr))
-            .or_insert(100);
+    // A configured peer, which is retried forever.
+    pub fn new_persistent(addr: SocketAddr) -> Self {
+        AddNodeReq {
+            addr,
+            persistent: true,
+            reserved: false,
+            source: None,
+        }
     }
-}
-
-pub struct DelNodeReq {
-    addr: SocketAddr,
-}
 
-impl DelNodeReq {
-    pub fn new(addr: SocketAddr) -> Self {
-        DelNodeReq { addr }
+    // A configured peer, which is always kept connected.
+    pub fn new_reserved(addr: SocketAddr) -> Self {
+        AddNodeReq {
+            addr,
+            persistent: true,
+            reserved: true,
+            source: None,
+        }
     }
 
-    pub fn handle(self, service: &mut NodesManager) {
-        service.known_addrs.remove(&RawAddr::from(self.addr));
+    // An address gossiped by the node on the session.
+    pub fn new_discovered(addr: SocketAddr, source: SessionId) -> Self {
+        AddNodeReq {
+            addr,
+            persistent: false,
+            reserved: false,
+            source: Some(source),
+        }
     }
-}
+
+    pub fn handle(self, service: &mut NodesManager) {
+        if service.ban_list.is_ip_banned(&self.addr.ip()) {
+            debug!("[AddNodeReq] Ignore banned address {:?}", self.addr);
+            return;
+        }
+
+        if service.validator_only && !self.persistent {
+            debug!(
+                "[AddNodeReq] Ignore {:?}, not a configured peer in validator-only mode",
+                self.addr
+            );
+            return;
+        }
+
+        let raw_addr = RawAddr::from(self.addr);
+        if !service.known_addrs.contains_key(&raw_addr) {
+            // The addresses are counted by the IP of the session gossiping them.
+            let source_ip = match self.source {
+                Some(session_id) => match service.sessions.get(session_id) {
+                    Some(session) => Some(session.addr.ip()),
+                    None => {
+                        debug!(
+                            "[AddNodeReq] Ignore {:?} from unknown session {}",
+                            self.addr, session_id
+                        );
+                        return;
+                    }
+                },
+                None => None,
+            };
+            let now = Instant::now();
+            if let Some(ip) = source_ip {
+                let count = match service.discovered_counts.get(&ip) {
+                    Some((count, start))
+                        if now.duration_since(*start) < DISCOVERED_ADDRS_WINDOW =>
+                    {
+                        *count
+                    }
+                    _ => 0,
+                };
+                if count >= service.max_addrs_per_session {
+                    debug!(
+                        "[AddNodeReq] Ignore {:?}, too many addresses from {:?}",
+                        self.addr, ip
+                    );
+                    return;
+                }
+            }
+            if !service.make_room(self.persistent) {
+                debug!("[AddNodeReq] Ignore {:?}, address book is full", self.addr);
+                return;
+            }
+            if let Some(ip) = source_ip {
+                let entry = service.discovered_counts.entry(ip).or_insert((0, now));
+                if now.duration_since(entry.1) >= DISCOVERED_ADDRS_WINDOW {
+                    *entry = (0, now);
+                }
+                entry.0 += 1;
+            }
+        }
+
+        service
+            .known_addrs
+            .entry(raw_addr.clone())
+            .or_insert_with(AddrInfo::default)
+            .seen();
+        if self.persistent {
+            if self.reserved {
+                service.reserved_addrs.insert(raw_addr.clone());
+            } else {
+                service.reserved_addrs.remove(&raw_addr);
+            }
+            service.persistent_addrs.insert(raw_addr);
+        }
+    }
+}
+
+pub struct DelNodeReq {
+    addr: SocketAddr,
+}
+
+impl DelNodeReq {
+    pub fn new(addr: SocketAddr) -> Self {
+        DelNodeReq { addr }
+    }
+
+    pub fn handle(self, service: &mut NodesManager) {
+        let raw_addr = RawAddr::from(self.addr);
+        service.persistent_addrs.remove(&raw_addr);
+        service.reserved_addrs.remove(&raw_addr);
+        service.remove_addr(&raw_addr);
+
+        // Close the sessions to the deleted node.
+        service.evict_sessions_to(&self.addr);
+    }
+}
+
+pub struct AddPeerHostReq {
+    host: String,
+    port: u16,
+    reserved: bool,
+}
+
+impl AddPeerHostReq {
+    pub fn new(host: String, port: u16, reserved: bool) -> Self {
+        AddPeerHostReq {
+            host,
+            port,
+            reserved,
+        }
+    }
+
+    pub fn handle(self, service: &mut NodesManager) {
+        let key = (self.host.clone(), self.port);
+        if self.reserved {
+            service.reserved_hosts.insert(key);
+        } else {
+            service.reserved_hosts.remove(&key);
+        }
+        service
+            .peer_hosts
+            .entry((self.host.clone(), self.port))
+            .or_insert_with(Vec::new);
+        service.resolve_peer_host(self.host, self.port);
+    }
+}
+
+pub struct PeerHostResolvedReq {
+    host: String,
+    port: u16,
+    result: io::Result<Vec<SocketAddr>>,
+}
+
+impl PeerHostResolvedReq {
+    pub fn new(host: String, port: u16, result: io::Result<Vec<SocketAddr>>) -> Self {
+        PeerHostResolvedReq { host, port, result }
+    }
+
+    // Replace the stale addresses of the host in known_addrs, and close the sessions to them.
+    // If the lookup fails, keep the addresses resolved last time.
+    pub fn handle(self, service: &mut NodesManager) {
+        let key = (self.host, self.port);
+        service.resolving.remove(&key);
+
+        let addrs: Vec<RawAddr> = match self.result {
+            Ok(addrs) => addrs.into_iter().map(RawAddr::from).collect(),
+            Err(err) => {
+                warn!(
+                    "[PeerHostResolvedReq] Resolve {}:{} failed : {:?}",
+                    key.0, key.1, err
+                );
+                return;
+            }
+        };
+        debug!(
+            "[PeerHostResolvedReq] Resolve {}:{} to {:?}",
+            key.0,
+            key.1,
+            addrs.iter().map(RawAddr::socket_addr).collect::<Vec<_>>()
+        );
+
+        // The host is deleted while resolving.
+        let resolved = match service.peer_hosts.get_mut(&key) {
+            Some(resolved) => std::mem::replace(resolved, addrs.clone()),
+            None => return,
+        };
+
+        for stale in resolved.iter().filter(|addr| !addrs.contains(addr)) {
+            service.known_addrs.remove(stale);
+            service.dial_scheduler.remove(&stale.socket_addr());
+            service.evict_sessions_to(&stale.socket_addr());
+        }
+        for addr in &addrs {
+            service
+                .known_addrs
+                .entry(addr.clone())
+                .or_insert_with(AddrInfo::default)
+                .seen();
+        }
+    }
+}
+
+pub struct DelPeerHostReq {
+    host: String,
+    port: u16,
+}
+
+impl DelPeerHostReq {
+    pub fn new(host: String, port: u16) -> Self {
+        DelPeerHostReq { host, port }
+    }
+
+    pub fn handle(self, service: &mut NodesManager) {
+        let key = (self.host, self.port);
+        service.reserved_hosts.remove(&key);
+        if let Some(addrs) = service.peer_hosts.remove(&key) {
+            for addr in addrs {
+                DelNodeReq::new(addr.socket_addr()).handle(service);
+            }
+        }
+    }
+}
 
 pub struct GetRandomNodesReq {
     num: usize,
@@ -267,12 +1530,20 @@ impl GetRandomNodesReq {
     }
 
     pub fn handle(self, service: &mut NodesManager) {
-        let addrs = service
+        let ban_list = &service.ban_list;
+        let candidates: Vec<(SocketAddr, i32)> = service
             .known_addrs
-            .keys()
-            .take(self.num)
-            .map(|addr| addr.socket_addr())
+            .iter()
+            .filter(|(addr, _)| !ban_list.is_ip_banned(&addr.socket_addr().ip()))
+            .map(|(addr, info)| (addr.socket_addr(), info.score))
             .collect();
+        let addrs = select_addrs(
+            &candidates,
+            self.num,
+            service.max_addrs_per_bucket,
+            &[],
+            &mut thread_rng(),
+        );
 
         match self.return_channel.try_send(addrs) {
             Ok(_) => {
@@ -285,6 +1556,101 @@ impl GetRandomNodesReq {
     }
 }
 
+pub struct SessionOpenReq {
+    addr: SocketAddr,
+    session_id: SessionId,
+    ty: SessionType,
+    public_key: Option<String>,
+}
+
+impl SessionOpenReq {
+    pub fn new(
+        addr: SocketAddr,
+        session_id: SessionId,
+        ty: SessionType,
+        public_key: Option<String>,
+    ) -> Self {
+        SessionOpenReq {
+            addr,
+            session_id,
+            ty,
+            public_key,
+        }
+    }
+
+    pub fn handle(self, service: &mut NodesManager) {
+        let is_banned = service.ban_list.is_ip_banned(&self.addr.ip())
+            || self
+                .public_key
+                .as_ref()
+                .map(|key| service.ban_list.is_key_banned(key))
+                .unwrap_or(false);
+        if is_banned {
+            service.metrics.rejected_banned += 1;
+            warn!(
+                "[SessionOpen] Reject session {} from {:?}, public key {:?} is banned",
+                self.session_id, self.addr, self.public_key
+            );
+            service.disconnect(self.session_id);
+            return;
+        }
+
+        if !service
+            .allowlist
+            .read()
+            .unwrap()
+            .is_allowed(self.public_key.as_ref().map(String::as_str))
+        {
+            service.metrics.rejected_unauthorized += 1;
+            warn!(
+                "[SessionOpen] Reject session {} from {:?}, public key {:?} is not allowed",
+                self.session_id, self.addr, self.public_key
+            );
+            service.disconnect(self.session_id);
+            return;
+        }
+
+        let session = SessionInfo::new(self.session_id, self.ty, self.addr, self.public_key);
+        if !service.is_configured_session(
+            session.dialed_addr.as_ref(),
+            session.public_key.as_ref().map(String::as_str),
+        ) {
+            service.metrics.rejected_unauthorized += 1;
+            warn!(
+                "[SessionOpen] Reject session {} from {:?}, not a configured peer in validator-only mode",
+                self.session_id, self.addr
+            );
+            service.disconnect(self.session_id);
+            return;
+        }
+
+        let inbound = session.is_inbound();
+        if !service.admit_session(&session) {
+            if inbound {
+                service.metrics.rejected_full += 1;
+            } else {
+                // Not a failure of the node, retry it after the shortest backoff.
+                service.dial_scheduler.fail(self.addr, 1, Instant::now());
+            }
+            warn!(
+                "[SessionOpen] Reject session {} with {:?}, {} sessions are full",
+                self.session_id,
+                self.addr,
+                if inbound { "inbound" } else { "outbound" }
+            );
+            service.disconnect(self.session_id);
+            return;
+        }
+
+        if !inbound {
+            service.update_score(&RawAddr::from(self.addr), ScoreEvent::DialSuccess);
+            service.dial_scheduler.succeed(&self.addr);
+            service.learn_peer_key(&self.addr, session.public_key.as_ref());
+        }
+        service.sessions.insert(session);
+    }
+}
+
 pub struct AddConnectedNodeReq {
     addr: SocketAddr,
     session_id: SessionId,
@@ -296,10 +1662,70 @@ impl AddConnectedNodeReq {
     }
 
     pub fn handle(self, service: &mut NodesManager) {
-        // FIXME: If have reached to max_connects, disconnected this node.
-        service
-            .connected_addrs
-            .insert(self.session_id, RawAddr::from(self.addr));
+        let raw_addr = RawAddr::from(self.addr);
+        service.update_score(&raw_addr, ScoreEvent::DialSuccess);
+        // A repeated connection means the dial is done too.
+        service.dial_scheduler.succeed(&self.addr);
+
+        // A repeated connection may be an inbound session from the dialed node,
+        // record the dialed address on it, so the node is not dialed again.
+        let public_key = service.sessions.get_mut(self.session_id).map(|session| {
+            session.dialed_addr = Some(self.addr);
+            session.public_key.clone()
+        });
+        if let Some(public_key) = public_key {
+            service.learn_peer_key(&self.addr, public_key.as_ref());
+        }
+    }
+}
+
+pub struct DialFailedReq {
+    addr: SocketAddr,
+}
+
+impl DialFailedReq {
+    pub fn new(addr: SocketAddr) -> Self {
+        DialFailedReq { addr }
+    }
+
+    pub fn handle(self, service: &mut NodesManager) {
+        service.dial_failed(self.addr);
+        // The dial slot is free, fill it now rather than on the next tick.
+        service.dial_nodes();
+    }
+}
+
+// Score the peer behind a session, such as a bad frame or a sync response.
+pub struct UpdateScoreReq {
+    session_id: SessionId,
+    event: ScoreEvent,
+}
+
+impl UpdateScoreReq {
+    pub fn new(session_id: SessionId, event: ScoreEvent) -> Self {
+        UpdateScoreReq { session_id, event }
+    }
+
+    pub fn handle(self, service: &mut NodesManager) {
+        if self.event == ScoreEvent::BadChecksum {
+            service.metrics.bad_checksums += 1;
+            service.sessions.record_checksum_failure(self.session_id);
+        }
+
+        // Inbound sessions have no dialed address, they are scored by IP.
+        let addrs = service
+            .sessions
+            .get(self.session_id)
+            .map(|session| (session.dialed_addr, session.addr.ip()));
+        match addrs {
+            Some((Some(addr), _)) => {
+                service.update_score(&RawAddr::from(addr), self.event);
+            }
+            Some((None, ip)) => {
+                service.score_ip(&ip, self.event);
+            }
+            None => {}
+        }
     }
 }
 
@@ -313,7 +1739,169 @@ impl DelConnectedNodeReq {
     }
 
     pub fn handle(self, service: &mut NodesManager) {
-        service.connected_addrs.remove(&self.session_id);
+        let session = service.sessions.remove(self.session_id);
+        service.fragment_queues.remove(&self.session_id);
+
+        for sessions in service.observed_addrs.values_mut() {
+            sessions.remove(&self.session_id);
+        }
+        service
+            .observed_addrs
+            .retain(|_, sessions| !sessions.is_empty());
+
+        if let Some(session) = session {
+            if service.is_reserved_session(&session) {
+                info!(
+                    "[DelConnectedNodeReq] Reserved node {:?} disconnected, dial it again",
+                    session.addr
+                );
+            }
+        }
+        // Fill the free session slot, and dial the reserved node again.
+        service.dial_nodes();
+    }
+}
+
+pub struct ProtocolOpenReq {
+    session_id: SessionId,
+    proto_id: ProtocolId,
+    version: String,
+}
+
+impl ProtocolOpenReq {
+    pub fn new(session_id: SessionId, proto_id: ProtocolId, version: String) -> Self {
+        ProtocolOpenReq {
+            session_id,
+            proto_id,
+            version,
+        }
+    }
+
+    pub fn handle(self, service: &mut NodesManager) {
+        // Tell the peer what we can read, if its transfer protocol knows the
+        // capabilities frame. The checksums are always checked.
+        if self.proto_id == TRANSFER_PROTOCOL_ID && self.version == TRANSFER_VERSION_CAPABILITIES {
+            let capabilities = Capabilities {
+                compression: service.compression.clone(),
+                checksum: true,
+                fragment: true,
+            };
+            let mut buf = BytesMut::new();
+            encode_network_message(
+                &mut buf,
+                FRAME_VERSION_1,
+                0,
+                Some((CAPABILITIES_KEY.to_owned(), capabilities.encode())),
+            );
+            if let Some(ref mut ctrl) = service.service_ctrl {
+                if let Err(err) = ctrl.send_message(
+                    Some(vec![self.session_id]),
+                    TRANSFER_PROTOCOL_ID,
+                    buf.to_vec(),
+                ) {
+                    warn!(
+                        "Send the capabilities to session [{}] failed: {:?}",
+                        self.session_id, err
+                    );
+                }
+            }
+        }
+
+        service
+            .sessions
+            .open_protocol(self.session_id, self.proto_id, self.version);
+    }
+}
+
+pub struct ProtocolCloseReq {
+    session_id: SessionId,
+    proto_id: ProtocolId,
+}
+
+impl ProtocolCloseReq {
+    pub fn new(session_id: SessionId, proto_id: ProtocolId) -> Self {
+        ProtocolCloseReq {
+            session_id,
+            proto_id,
+        }
+    }
+
+    pub fn handle(self, service: &mut NodesManager) {
+        service
+            .sessions
+            .close_protocol(self.session_id, self.proto_id);
+    }
+}
+
+// Count a transfer message received on the session.
+// Messages received on a session since the last report, counted by the protocol handle.
+pub struct MessageReceivedReq {
+    session_id: SessionId,
+    bytes: usize,
+    messages: usize,
+}
+
+impl MessageReceivedReq {
+    pub fn new(session_id: SessionId, bytes: usize, messages: usize) -> Self {
+        MessageReceivedReq {
+            session_id,
+            bytes,
+            messages,
+        }
+    }
+
+    pub fn handle(self, service: &mut NodesManager) {
+        service
+            .sessions
+            .record_received(self.session_id, self.bytes, self.messages);
+    }
+}
+
+// What the peer can read, from its capabilities frame.
+pub struct PeerCapabilitiesReq {
+    session_id: SessionId,
+    capabilities: Capabilities,
+}
+
+impl PeerCapabilitiesReq {
+    pub fn new(session_id: SessionId, capabilities: Capabilities) -> Self {
+        PeerCapabilitiesReq {
+            session_id,
+            capabilities,
+        }
+    }
+
+    pub fn handle(self, service: &mut NodesManager) {
+        let compression = negotiate(&service.compression, &self.capabilities.compression);
+        let checksum = service.enable_checksum && self.capabilities.checksum;
+        if let Some(session) = service.sessions.get_mut(self.session_id) {
+            info!(
+                "Session [{}] negotiated compression: {:?}, checksum: {}, fragment: {}",
+                self.session_id, compression, checksum, self.capabilities.fragment
+            );
+            session.compression = compression;
+            session.checksum = checksum;
+            session.fragment = self.capabilities.fragment;
+        }
+    }
+}
+
+pub struct GetSessionsReq {
+    return_channel: crossbeam_channel::Sender<Vec<SessionInfo>>,
+}
+
+impl GetSessionsReq {
+    pub fn new(return_channel: crossbeam_channel::Sender<Vec<SessionInfo>>) -> Self {
+        GetSessionsReq { return_channel }
+    }
+
+    pub fn handle(self, service: &mut NodesManager) {
+        let mut sessions: Vec<SessionInfo> = service.sessions.iter().cloned().collect();
+        sessions.sort_by_key(|session| session.id);
+
+        if let Err(err) = self.return_channel.try_send(sessions) {
+            warn!("Get sessions, send them failed : {:?}", err);
+        }
     }
 }
 
@@ -332,11 +1920,13 @@ impl BroadcastReq {
         trace!("Broadcast msg {:?}, from key {}", self.msg, self.key);
         let msg_bytes: Vec<u8> = self.msg.try_into().unwrap();
 
-        let mut buf = BytesMut::with_capacity(4 + 4 + 1 + self.key.len() + msg_bytes.len());
-        pubsub_message_to_network_message(&mut buf, Some((self.key, msg_bytes)));
-        if let Some(ref mut ctrl) = service.service_ctrl {
-            let _ = ctrl.send_message(None, 1, buf.to_vec());
-        }
+        let session_ids: Vec<SessionId> = service
+            .sessions
+            .iter()
+            .filter(|session| session.protocols.contains_key(&TRANSFER_PROTOCOL_ID))
+            .map(|session| session.id)
+            .collect();
+        service.send_transfer(session_ids, self.key, msg_bytes);
     }
 }
 
@@ -360,12 +1950,7 @@ impl SingleTxReq {
         );
         let msg_bytes: Vec<u8> = self.msg.try_into().unwrap();
 
-        let mut buf = BytesMut::with_capacity(4 + 4 + 1 + self.key.len() + msg_bytes.len());
-        pubsub_message_to_network_message(&mut buf, Some((self.key, msg_bytes)));
-        if let Some(ref mut ctrl) = service.service_ctrl {
-            //FIXME: handle the error!
-            let _ = ctrl.send_message(Some(vec![self.dst]), 1, buf.to_vec());
-        }
+        service.send_transfer(vec![self.dst], self.key, msg_bytes);
     }
 }
 
@@ -379,7 +1964,7 @@ impl GetPeerCountReq {
     }
 
     pub fn handle(self, service: &mut NodesManager) {
-        let peer_count = service.connected_addrs.len();
+        let peer_count = service.sessions.len();
 
         match self.return_channel.try_send(peer_count) {
             Ok(_) => {
@@ -394,3 +1979,1105 @@ impl GetPeerCountReq {
         }
     }
 }
+
+pub struct SetMaxConnectsReq {
+    max_inbound: usize,
+    max_outbound: usize,
+}
+
+impl SetMaxConnectsReq {
+    pub fn new(max_inbound: usize, max_outbound: usize) -> Self {
+        SetMaxConnectsReq {
+            max_inbound,
+            max_outbound,
+        }
+    }
+
+    pub fn handle(self, service: &mut NodesManager) {
+        service.max_inbound = self.max_inbound;
+        service.max_outbound = self.max_outbound;
+        service.enforce_session_limits();
+    }
+}
+
+pub struct SetAllowedKeysReq {
+    keys: Vec<String>,
+}
+
+impl SetAllowedKeysReq {
+    pub fn new(keys: Vec<String>) -> Self {
+        SetAllowedKeysReq { keys }
+    }
+
+    pub fn handle(self, service: &mut NodesManager) {
+        service
+            .allowlist
+            .write()
+            .unwrap()
+            .set_config_keys(self.keys);
+    }
+}
+
+pub struct BanReq {
+    target: BanTarget,
+    duration: Option<Duration>,
+    reason: String,
+}
+
+impl BanReq {
+    // Ban the target for `duration`, or forever if it is None.
+    pub fn new(target: BanTarget, duration: Option<Duration>, reason: String) -> Self {
+        BanReq {
+            target,
+            duration,
+            reason,
+        }
+    }
+
+    pub fn handle(self, service: &mut NodesManager) {
+        // Close the sessions to the banned addresses or public key.
+        let session_ids: Vec<SessionId> = service
+            .sessions
+            .iter()
+            .filter(|session| match self.target {
+                BanTarget::PublicKey(ref key) => session
+                    .public_key
+                    .as_ref()
+                    .map(|session_key| normalize_key(session_key) == normalize_key(key))
+                    .unwrap_or(false),
+                _ => {
+                    self.target.contains(&session.addr.ip())
+                        || session
+                            .dialed_addr
+                            .map(|addr| self.target.contains(&addr.ip()))
+                            .unwrap_or(false)
+                }
+            })
+            .map(|session| session.id)
+            .collect();
+        for session_id in session_ids {
+            service.evict_session(session_id);
+        }
+
+        service
+            .ban_list
+            .ban(self.target, self.duration, self.reason);
+    }
+}
+
+// Penalize a node reported by discovery, and ban it when the score is too low.
+pub struct MisbehaveReq {
+    addr: SocketAddr,
+    misbehavior: Misbehavior,
+    return_channel: crossbeam_channel::Sender<i32>,
+}
+
+impl MisbehaveReq {
+    pub fn new(
+        addr: SocketAddr,
+        misbehavior: Misbehavior,
+        return_channel: crossbeam_channel::Sender<i32>,
+    ) -> Self {
+        MisbehaveReq {
+            addr,
+            misbehavior,
+            return_channel,
+        }
+    }
+
+    pub fn handle(self, service: &mut NodesManager) {
+        let ip = self.addr.ip();
+        let misbehavior = self.misbehavior;
+        let mut score = service.score_ip(&ip, ScoreEvent::Misbehave(misbehavior));
+        warn!(
+            "[misbehave] Node {:?} misbehave, {:?}, score: {}",
+            self.addr, misbehavior, score
+        );
+
+        // A configured peer, such as a validator, is never banned. Close the session,
+        // and it is dialed again with a fresh score.
+        let is_configured = service.is_persistent(&RawAddr::from(self.addr))
+            || service.sessions.iter().any(|session| {
+                session.addr == self.addr && service.session_peer(session).is_some()
+            });
+        if score < service.misbehave_ban_score && is_configured {
+            let session_ids: Vec<SessionId> = service
+                .sessions
+                .iter()
+                .filter(|session| session.addr == self.addr)
+                .map(|session| session.id)
+                .collect();
+            for session_id in session_ids {
+                service.evict_session(session_id);
+            }
+            service.reset_ip_score(&ip);
+            score = DEFAULT_SCORE;
+        } else if score < service.misbehave_ban_score {
+            service.ip_scores.remove(&ip);
+            BanReq::new(
+                BanTarget::Ip(ip),
+                Some(service.misbehave_ban_duration),
+                format!("misbehave, {:?}", misbehavior),
+            )
+            .handle(service);
+        }
+
+        if let Err(err) = self.return_channel.try_send(score) {
+            warn!("[misbehave] Send score failed : {:?}", err);
+        }
+    }
+}
+
+pub struct UnbanReq {
+    target: BanTarget,
+}
+
+impl UnbanReq {
+    pub fn new(target: BanTarget) -> Self {
+        UnbanReq { target }
+    }
+
+    pub fn handle(self, service: &mut NodesManager) {
+        service.ban_list.unban(&self.target);
+    }
+}
+
+pub struct GetMetricsReq {
+    return_channel: crossbeam_channel::Sender<NetworkMetrics>,
+}
+
+impl GetMetricsReq {
+    pub fn new(return_channel: crossbeam_channel::Sender<NetworkMetrics>) -> Self {
+        GetMetricsReq { return_channel }
+    }
+
+    pub fn handle(self, service: &mut NodesManager) {
+        if let Err(err) = self.return_channel.try_send(service.metrics.clone()) {
+            warn!("Get metrics, send them failed : {:?}", err);
+        }
+    }
+}
+
+// Save the data, and stop the loop of the nodes manager, the process exits then.
+#[derive(Default)]
+pub struct ShutdownReq;
+
+impl ShutdownReq {
+    pub fn new() -> Self {
+        ShutdownReq
+    }
+
+    pub fn handle(self, service: &mut NodesManager) {
+        service.save_address_book();
+        service.save_metrics();
+        service.shutdown = true;
+    }
+}
+
+pub struct GetAdvertiseAddrsReq {
+    return_channel: crossbeam_channel::Sender<Vec<Multiaddr>>,
+}
+
+impl GetAdvertiseAddrsReq {
+    pub fn new(return_channel: crossbeam_channel::Sender<Vec<Multiaddr>>) -> Self {
+        GetAdvertiseAddrsReq { return_channel }
+    }
+
+    pub fn handle(self, service: &mut NodesManager) {
+        let addrs = service.advertise_addrs();
+
+        match self.return_channel.try_send(addrs) {
+            Ok(_) => {
+                debug!("Get advertise addresses and send them success");
+            }
+            Err(err) => {
+                warn!("Get advertise addresses, send them failed : {:?}", err);
+            }
+        }
+    }
+}
+
+pub struct AddObservedAddrReq {
+    session_id: SessionId,
+    addr: Multiaddr,
+}
+
+impl AddObservedAddrReq {
+    pub fn new(session_id: SessionId, addr: Multiaddr) -> Self {
+        AddObservedAddrReq { session_id, addr }
+    }
+
+    pub fn handle(self, service: &mut NodesManager) {
+        if !service.learn_external_addr {
+            return;
+        }
+
+        // Inbound peers choose the session, so only the ones we dialed are trusted.
+        let peer_bucket = match service.sessions.get(self.session_id) {
+            Some(session) if !session.is_inbound() => bucket(&session.addr.ip()),
+            _ => return,
+        };
+
+        // Only the IP is useful, the port observed by an outbound session is a random one.
+        if let Some(socket_addr) = multiaddr_to_socketaddr(&self.addr) {
+            service
+                .observed_addrs
+                .entry(socket_addr.ip())
+                .or_insert_with(HashMap::default)
+                .insert(self.session_id, peer_bucket);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        AddConnectedNodeReq, AddNodeReq, AddObservedAddrReq, BanReq, DelConnectedNodeReq,
+        DelNodeReq, DialFailedReq, GetPeerCountReq, GetRandomNodesReq, MisbehaveReq, NodesManager,
+        PeerCapabilitiesReq, SessionOpenReq, SetMaxConnectsReq, ShutdownReq, UpdateScoreReq,
+        DISCOVERED_ADDRS_WINDOW, IP_SCORE_TTL,
+    };
+    use crate::address_book::{ADDRESS_BOOK_FILE, DEFAULT_SCORE};
+    use crate::ban_list::BanTarget;
+    use crate::citaprotocol::{decode_network_message, FLAG_CHECKSUM};
+    use crate::compression::{Capabilities, Compression};
+    use crate::config::tests::config_from_str;
+    use crate::config::NetConfig;
+    use crate::config_watcher::ConfigWatcher;
+    use crate::fragment::Reassembler;
+    use crate::metrics::METRICS_FILE;
+    use crate::peer_score::{Misbehavior, ScoreEvent};
+    use crate::resolver::Resolver;
+    use bytes::BytesMut;
+    use crossbeam_channel::unbounded;
+    use discovery::RawAddr;
+    use p2p::{multiaddr::Multiaddr, SessionId, SessionType};
+    use std::collections::HashMap;
+    use std::fs;
+    use std::io;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use tempfile::{tempdir, NamedTempFile};
+
+    #[derive(Clone, Default)]
+    struct StubResolver {
+        hosts: Arc<Mutex<HashMap<String, Vec<SocketAddr>>>>,
+    }
+
+    impl StubResolver {
+        fn set(&self, host: &str, addrs: &[&str]) {
+            let addrs = addrs.iter().map(|addr| addr.parse().unwrap()).collect();
+            self.hosts.lock().unwrap().insert(host.to_owned(), addrs);
+        }
+
+        fn unset(&self, host: &str) {
+            self.hosts.lock().unwrap().remove(host);
+        }
+    }
+
+    impl Resolver for StubResolver {
+        fn resolve(&self, host: &str, _port: u16) -> io::Result<Vec<SocketAddr>> {
+            self.hosts
+                .lock()
+                .unwrap()
+                .get(host)
+                .cloned()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "no such host"))
+        }
+    }
+
+    // Resolve the peer hostnames, and wait for the results.
+    fn resolve(mgr: &mut NodesManager) {
+        mgr.resolve_peer_hosts();
+        while !mgr.resolving.is_empty() {
+            let msg = mgr
+                .nodes_manager_service_receiver
+                .recv_timeout(Duration::from_secs(5))
+                .unwrap();
+            msg.handle(mgr);
+        }
+    }
+
+    fn is_known(mgr: &NodesManager, addr: &str) -> bool {
+        let addr: SocketAddr = addr.parse().unwrap();
+        mgr.known_addrs.contains_key(&RawAddr::from(addr))
+    }
+
+    #[test]
+    fn resolve_peer_hosts() {
+        let config = config_from_str(
+            r#"
+            port = 4000
+            [[peers]]
+                ip = "127.0.0.1"
+                port = 4001
+            [[peers]]
+                ip = "node-2.cita"
+                port = 4002
+            "#,
+        );
+        let resolver = StubResolver::default();
+        let mut mgr = NodesManager::from_config(config);
+        mgr.set_resolver(Arc::new(resolver.clone()));
+
+        // Lookup failed, nothing is added.
+        resolve(&mut mgr);
+        assert_eq!(mgr.known_addrs.len(), 1);
+
+        resolver.set("node-2.cita", &["10.0.0.2:4002", "10.0.0.3:4002"]);
+        resolve(&mut mgr);
+        assert_eq!(mgr.known_addrs.len(), 3);
+        assert!(is_known(&mgr, "10.0.0.2:4002"));
+        assert!(is_known(&mgr, "10.0.0.3:4002"));
+        let addr = "10.0.0.2:4002".parse().unwrap();
+        SessionOpenReq::new(addr, 1, SessionType::Client, None).handle(&mut mgr);
+        assert!(mgr.sessions.contains(1));
+
+        // The IP changes, the stale one is replaced and its session is closed.
+        resolver.set("node-2.cita", &["10.0.0.4:4002", "10.0.0.3:4002"]);
+        resolve(&mut mgr);
+        assert_eq!(mgr.known_addrs.len(), 3);
+        assert!(!is_known(&mgr, "10.0.0.2:4002"));
+        assert!(is_known(&mgr, "10.0.0.3:4002"));
+        assert!(is_known(&mgr, "10.0.0.4:4002"));
+        assert!(!mgr.sessions.contains(1));
+
+        // Lookup failed, keep the addresses resolved last time.
+        resolver.unset("node-2.cita");
+        resolve(&mut mgr);
+        assert_eq!(mgr.known_addrs.len(), 3);
+        assert!(is_known(&mgr, "127.0.0.1:4001"));
+        assert!(is_known(&mgr, "10.0.0.4:4002"));
+    }
+
+    #[test]
+    fn skip_banned_addrs() {
+        let mut mgr = NodesManager::default();
+        BanReq::new(
+            BanTarget::Subnet("10.0.0.0".parse().unwrap(), 8),
+            None,
+            "test".to_owned(),
+        )
+        .handle(&mut mgr);
+
+        AddNodeReq::new("10.0.0.1:4000".parse().unwrap()).handle(&mut mgr);
+        AddNodeReq::new("192.168.0.1:4000".parse().unwrap()).handle(&mut mgr);
+        assert!(!is_known(&mgr, "10.0.0.1:4000"));
+        assert!(is_known(&mgr, "192.168.0.1:4000"));
+
+        // Known before banned, but never handed out.
+        BanReq::new(
+            BanTarget::Ip("192.168.0.1".parse().unwrap()),
+            None,
+            "test".to_owned(),
+        )
+        .handle(&mut mgr);
+        let (tx, rx) = unbounded();
+        GetRandomNodesReq::new(10, tx).handle(&mut mgr);
+        assert!(rx.recv().unwrap().is_empty());
+
+        // Sessions with a banned public key are closed.
+        let addr = "172.16.0.1:50001".parse().unwrap();
+        SessionOpenReq::new(addr, 1, SessionType::Server, Some("abcd".to_owned())).handle(&mut mgr);
+        assert!(mgr.sessions.contains(1));
+        BanReq::new(
+            BanTarget::PublicKey("ABCD".to_owned()),
+            None,
+            "test".to_owned(),
+        )
+        .handle(&mut mgr);
+        assert!(!mgr.sessions.contains(1));
+
+        // A key banned in any case or with "0x" refuses the session with the hex key.
+        BanReq::new(
+            BanTarget::PublicKey("0x02EF".to_owned()),
+            None,
+            "test".to_owned(),
+        )
+        .handle(&mut mgr);
+        SessionOpenReq::new(addr, 2, SessionType::Server, Some("02ef".to_owned())).handle(&mut mgr);
+        assert!(!mgr.sessions.contains(2));
+        assert_eq!(mgr.metrics.rejected_banned, 1);
+    }
+
+    #[test]
+    fn skip_banned_saved_addrs() {
+        let dir = tempdir().unwrap();
+        let toml_str = format!("port = 4000\ndata_dir = \"{}\"\n", dir.path().display());
+
+        let mut mgr = NodesManager::from_config(config_from_str(&toml_str));
+        AddNodeReq::new("10.0.0.1:4000".parse().unwrap()).handle(&mut mgr);
+        AddNodeReq::new("10.0.0.2:4000".parse().unwrap()).handle(&mut mgr);
+        BanReq::new(
+            BanTarget::Ip("10.0.0.1".parse().unwrap()),
+            None,
+            "test".to_owned(),
+        )
+        .handle(&mut mgr);
+        mgr.save_address_book();
+
+        let mgr = NodesManager::from_config(config_from_str(&toml_str));
+        assert!(!is_known(&mgr, "10.0.0.1:4000"));
+        assert!(is_known(&mgr, "10.0.0.2:4000"));
+    }
+
+    #[test]
+    fn score_known_addrs() {
+        let mut mgr = NodesManager::default();
+        AddNodeReq::new("10.0.0.1:4000".parse().unwrap()).handle(&mut mgr);
+        AddNodeReq::new("10.0.0.2:4000".parse().unwrap()).handle(&mut mgr);
+
+        // The node with higher score is handed out more often.
+        let low: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        let high: SocketAddr = "10.0.0.2:4000".parse().unwrap();
+        DialFailedReq::new(low).handle(&mut mgr);
+        let mut counts: HashMap<SocketAddr, usize> = HashMap::new();
+        for _ in 0..20000 {
+            let (tx, rx) = unbounded();
+            GetRandomNodesReq::new(1, tx).handle(&mut mgr);
+            for addr in rx.recv().unwrap() {
+                *counts.entry(addr).or_insert(0) += 1;
+            }
+        }
+        assert!(counts[&high] > counts[&low]);
+
+        // Removed once the retry budget is used up.
+        for _ in 0..4 {
+            assert!(is_known(&mgr, "10.0.0.1:4000"));
+            DialFailedReq::new("10.0.0.1:4000".parse().unwrap()).handle(&mut mgr);
+        }
+        assert!(!is_known(&mgr, "10.0.0.1:4000"));
+        assert!(is_known(&mgr, "10.0.0.2:4000"));
+    }
+
+    #[test]
+    fn misbehave() {
+        let addr: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        let cases = [
+            (Misbehavior::DuplicateGetNodes, 90),
+            (Misbehavior::DuplicateFirstNodes, 90),
+            (Misbehavior::TooManyItems, 80),
+            (Misbehavior::TooManyAddresses, 80),
+            (Misbehavior::InvalidData, 50),
+            (Misbehavior::from_code(100), 90),
+        ];
+        for (misbehavior, score) in cases.iter() {
+            let mut mgr = NodesManager::default();
+            let (tx, rx) = unbounded();
+            MisbehaveReq::new(addr, *misbehavior, tx).handle(&mut mgr);
+            assert_eq!(rx.recv().unwrap(), *score);
+            assert!(!mgr.ban_list.is_ip_banned(&addr.ip()));
+        }
+
+        // The penalty lowers the score of a known address.
+        let mut mgr = NodesManager::default();
+        AddNodeReq::new(addr).handle(&mut mgr);
+        let (tx, rx) = unbounded();
+        MisbehaveReq::new(addr, Misbehavior::TooManyItems, tx).handle(&mut mgr);
+        assert_eq!(rx.recv().unwrap(), 80);
+        assert_eq!(mgr.known_addrs[&RawAddr::from(addr)].score, 80);
+        assert!(mgr.ip_scores.is_empty());
+
+        // Banned once the score drops below the floor, and the session is closed.
+        let mut mgr = NodesManager::default();
+        SessionOpenReq::new(addr, 1, SessionType::Server, None).handle(&mut mgr);
+        let (tx, rx) = unbounded();
+        MisbehaveReq::new(addr, Misbehavior::InvalidData, tx.clone()).handle(&mut mgr);
+        MisbehaveReq::new(addr, Misbehavior::TooManyItems, tx).handle(&mut mgr);
+        assert_eq!(rx.recv().unwrap(), 50);
+        assert_eq!(rx.recv().unwrap(), 30);
+        assert!(mgr.ban_list.is_ip_banned(&addr.ip()));
+        assert!(!mgr.sessions.contains(1));
+        AddNodeReq::new(addr).handle(&mut mgr);
+        assert!(!is_known(&mgr, "10.0.0.1:4000"));
+
+        // The score of an IP without a session is forgotten after a while.
+        let mut mgr = NodesManager::default();
+        let (tx, _rx) = unbounded();
+        MisbehaveReq::new(addr, Misbehavior::TooManyItems, tx).handle(&mut mgr);
+        mgr.prune_ip_scores(Instant::now());
+        assert_eq!(mgr.ip_scores.len(), 1);
+        mgr.prune_ip_scores(Instant::now() + IP_SCORE_TTL);
+        assert!(mgr.ip_scores.is_empty());
+    }
+
+    #[test]
+    fn misbehave_configured_peers() {
+        let config = config_from_str(
+            r#"
+            port = 4000
+            [[peers]]
+                ip = "10.0.0.1"
+                port = 4000
+            [[peers]]
+                ip = "10.0.0.2"
+                port = 4000
+                reserved = true
+            "#,
+        );
+        let mut mgr = NodesManager::from_config(config);
+        let misbehave = |mgr: &mut NodesManager, addr: SocketAddr| {
+            let (tx, rx) = unbounded();
+            MisbehaveReq::new(addr, Misbehavior::InvalidData, tx.clone()).handle(mgr);
+            MisbehaveReq::new(addr, Misbehavior::TooManyItems, tx).handle(mgr);
+            rx.try_iter().last().unwrap()
+        };
+
+        // Sessions dialed to the persistent and the reserved peers are closed, not banned.
+        for (session_id, addr) in &[(1, "10.0.0.1:4000"), (2, "10.0.0.2:4000")] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            let key = format!("0{}", session_id);
+            SessionOpenReq::new(addr, *session_id, SessionType::Client, Some(key)).handle(&mut mgr);
+            assert!(mgr.sessions.contains(*session_id));
+
+            assert_eq!(misbehave(&mut mgr, addr), DEFAULT_SCORE);
+            assert!(!mgr.ban_list.is_ip_banned(&addr.ip()));
+            assert!(!mgr.sessions.contains(*session_id));
+            assert_eq!(mgr.known_addrs[&RawAddr::from(addr)].score, DEFAULT_SCORE);
+        }
+
+        // An inbound session from the key of a configured peer is protected too.
+        let addr: SocketAddr = "10.0.0.1:50003".parse().unwrap();
+        SessionOpenReq::new(addr, 3, SessionType::Server, Some("01".to_owned())).handle(&mut mgr);
+        assert!(mgr.sessions.contains(3));
+        assert_eq!(misbehave(&mut mgr, addr), DEFAULT_SCORE);
+        assert!(!mgr.ban_list.is_ip_banned(&addr.ip()));
+        assert!(!mgr.sessions.contains(3));
+
+        // Any other node is banned.
+        let addr: SocketAddr = "10.0.0.4:50004".parse().unwrap();
+        SessionOpenReq::new(addr, 4, SessionType::Server, Some("04".to_owned())).handle(&mut mgr);
+        assert_eq!(misbehave(&mut mgr, addr), 30);
+        assert!(mgr.ban_list.is_ip_banned(&addr.ip()));
+        assert!(!mgr.sessions.contains(4));
+    }
+
+    #[test]
+    fn retry_persistent_peers() {
+        let config = config_from_str(
+            r#"
+            port = 4000
+            [[peers]]
+                ip = "127.0.0.1"
+                port = 4001
+            "#,
+        );
+        let mut mgr = NodesManager::from_config(config);
+        let persistent: SocketAddr = "127.0.0.1:4001".parse().unwrap();
+        let discovered: SocketAddr = "127.0.0.1:4002".parse().unwrap();
+        AddNodeReq::new(discovered).handle(&mut mgr);
+
+        for _ in 0..20 {
+            DialFailedReq::new(persistent).handle(&mut mgr);
+            DialFailedReq::new(discovered).handle(&mut mgr);
+        }
+        assert!(is_known(&mgr, "127.0.0.1:4001"));
+        assert!(!is_known(&mgr, "127.0.0.1:4002"));
+
+        // Not dialed again until the backoff is over.
+        let raw_addr = RawAddr::from(persistent);
+        assert!(mgr
+            .dial_scheduler
+            .is_backing_off(&persistent, Instant::now()));
+        AddConnectedNodeReq::new(persistent, 1).handle(&mut mgr);
+        assert!(!mgr
+            .dial_scheduler
+            .is_backing_off(&persistent, Instant::now()));
+        assert_eq!(mgr.known_addrs[&raw_addr].failure_count, 0);
+    }
+
+    #[test]
+    fn inbound_quota() {
+        let mut mgr = NodesManager::default();
+        mgr.max_inbound = 2;
+        let open = |mgr: &mut NodesManager, session_id: SessionId, addr: &str| {
+            let addr = addr.parse().unwrap();
+            SessionOpenReq::new(addr, session_id, SessionType::Server, None).handle(mgr);
+        };
+
+        open(&mut mgr, 1, "10.0.0.1:50001");
+        open(&mut mgr, 2, "10.0.0.2:50002");
+        // Full, and no session has a lower score.
+        open(&mut mgr, 3, "10.0.0.3:50003");
+        assert_eq!(mgr.sessions.len(), 2);
+        assert!(!mgr.sessions.contains(3));
+        assert_eq!(mgr.metrics.rejected_full, 1);
+
+        // The session with the lowest score is evicted.
+        let (tx, _rx) = unbounded();
+        MisbehaveReq::new(
+            "10.0.0.1:50001".parse().unwrap(),
+            Misbehavior::DuplicateGetNodes,
+            tx,
+        )
+        .handle(&mut mgr);
+        open(&mut mgr, 4, "10.0.0.4:50004");
+        assert!(!mgr.sessions.contains(1));
+        assert!(mgr.sessions.contains(4));
+
+        // Persistent nodes are protected, and evict the unprotected sessions,
+        // but the limit still holds.
+        for i in 5..8 {
+            let addr: SocketAddr = format!("10.0.0.{}:4000", i).parse().unwrap();
+            AddNodeReq::new_persistent(addr).handle(&mut mgr);
+            mgr.peer_keys
+                .insert(RawAddr::from(addr), format!("{:02}", i));
+        }
+        let open_with_key =
+            |mgr: &mut NodesManager, session_id: SessionId, addr: &str, key: &str| {
+                let addr = addr.parse().unwrap();
+                SessionOpenReq::new(addr, session_id, SessionType::Server, Some(key.to_owned()))
+                    .handle(mgr);
+            };
+        open_with_key(&mut mgr, 5, "10.0.0.5:50005", "05");
+        open_with_key(&mut mgr, 6, "10.0.0.6:50006", "06");
+        open_with_key(&mut mgr, 7, "10.0.0.7:50007", "07");
+        let mut sessions: Vec<SessionId> = mgr.sessions.iter().map(|session| session.id).collect();
+        sessions.sort();
+        assert_eq!(sessions, vec![5, 6]);
+
+        // Not protected by the IP alone.
+        mgr.max_inbound = 3;
+        open(&mut mgr, 8, "10.0.0.2:50008");
+        open_with_key(&mut mgr, 9, "10.0.0.5:50009", "09");
+        assert!(mgr.sessions.contains(8));
+        assert!(!mgr.sessions.contains(9));
+
+        let (tx, rx) = unbounded();
+        GetPeerCountReq::new(tx).handle(&mut mgr);
+        assert_eq!(rx.recv().unwrap(), 3);
+    }
+
+    #[test]
+    fn reserved_peers() {
+        let config = config_from_str(
+            r#"
+            port = 4000
+            max_inbound = 1
+            [[peers]]
+                ip = "10.0.0.9"
+                port = 4000
+                reserved = true
+            "#,
+        );
+        let mut mgr = NodesManager::from_config(config);
+        mgr.max_outbound = 0;
+        let reserved = RawAddr::from("10.0.0.9:4000".parse::<SocketAddr>().unwrap());
+        assert!(mgr.is_reserved(&reserved));
+        let open = |mgr: &mut NodesManager, session_id: SessionId, addr: &str, key: &str| {
+            let addr = addr.parse().unwrap();
+            SessionOpenReq::new(addr, session_id, SessionType::Server, Some(key.to_owned()))
+                .handle(mgr);
+        };
+
+        // Reserved sessions take no room, and evict nothing.
+        open(&mut mgr, 1, "10.0.0.1:50001", "01");
+        SessionOpenReq::new(
+            reserved.socket_addr(),
+            2,
+            SessionType::Client,
+            Some("09".to_owned()),
+        )
+        .handle(&mut mgr);
+        assert!(mgr.sessions.contains(1));
+        assert!(mgr.sessions.contains(2));
+        open(&mut mgr, 3, "10.0.0.3:50003", "03");
+        assert!(!mgr.sessions.contains(3));
+
+        // One session for the reserved peer, and another node on the IP is not reserved.
+        open(&mut mgr, 4, "10.0.0.9:50004", "09");
+        open(&mut mgr, 5, "10.0.0.9:50005", "05");
+        assert!(!mgr.sessions.contains(4));
+        assert!(!mgr.sessions.contains(5));
+
+        // Never evicted to meet the limits.
+        SetMaxConnectsReq::new(0, 0).handle(&mut mgr);
+        assert!(!mgr.sessions.contains(1));
+        assert!(mgr.sessions.contains(2));
+
+        // Added again without the flag.
+        AddNodeReq::new_persistent(reserved.socket_addr()).handle(&mut mgr);
+        assert!(!mgr.is_reserved(&reserved));
+    }
+
+    #[test]
+    fn reject_unallowed_keys() {
+        let dir = tempdir().unwrap();
+        let config = config_from_str(&format!(
+            r#"
+            port = 4000
+            data_dir = "{}"
+            allowed_keys = ["0xABCD"]
+            "#,
+            dir.path().display()
+        ));
+        let mut mgr = NodesManager::from_config(config);
+        let open = |mgr: &mut NodesManager, session_id: SessionId, key: Option<&str>| {
+            let addr = format!("10.0.0.{}:50000", session_id).parse().unwrap();
+            let key = key.map(str::to_owned);
+            SessionOpenReq::new(addr, session_id, SessionType::Server, key).handle(mgr);
+        };
+
+        open(&mut mgr, 1, Some("beef"));
+        open(&mut mgr, 2, None);
+        open(&mut mgr, 3, Some("abcd"));
+        assert!(!mgr.sessions.contains(1));
+        assert!(!mgr.sessions.contains(2));
+        assert!(mgr.sessions.contains(3));
+        assert_eq!(mgr.metrics.rejected_unauthorized, 2);
+
+        mgr.save_metrics();
+        let text = fs::read_to_string(dir.path().join(METRICS_FILE)).unwrap();
+        assert!(text.contains("cita_network_rejected_sessions_total{reason=\"unauthorized\"} 2\n"));
+    }
+
+    #[test]
+    fn validator_only() {
+        let config = config_from_str(
+            r#"
+            port = 4000
+            validator_only = true
+            [[peers]]
+                ip = "10.0.0.9"
+                port = 4000
+            "#,
+        );
+        let mut mgr = NodesManager::from_config(config);
+        let open = |mgr: &mut NodesManager, session_id: SessionId, addr: &str, key: &str| {
+            let addr = addr.parse().unwrap();
+            SessionOpenReq::new(addr, session_id, SessionType::Server, Some(key.to_owned()))
+                .handle(mgr);
+        };
+
+        // The configured peer is dialed, and its public key is learned.
+        let peer = "10.0.0.9:4000".parse().unwrap();
+        SessionOpenReq::new(peer, 1, SessionType::Client, Some("09".to_owned())).handle(&mut mgr);
+        assert!(mgr.sessions.contains(1));
+        DelConnectedNodeReq::new(1).handle(&mut mgr);
+
+        // Matched by the public key, not by the IP.
+        open(&mut mgr, 2, "10.0.0.9:50002", "09");
+        open(&mut mgr, 3, "10.0.0.9:50003", "03");
+        open(&mut mgr, 4, "10.0.0.1:50004", "04");
+        assert!(mgr.sessions.contains(2));
+        assert!(!mgr.sessions.contains(3));
+        assert!(!mgr.sessions.contains(4));
+        assert_eq!(mgr.metrics.rejected_unauthorized, 2);
+
+        // The addresses not configured are not learned.
+        AddNodeReq::new_discovered("10.0.0.2:4000".parse().unwrap(), 2).handle(&mut mgr);
+        assert!(!is_known(&mgr, "10.0.0.2:4000"));
+        assert_eq!(mgr.known_addrs.len(), 1);
+
+        // Accepted by an allowed key.
+        mgr.allowlist
+            .write()
+            .unwrap()
+            .set_config_keys(vec!["abcd".to_owned()]);
+        open(&mut mgr, 5, "10.0.0.3:50005", "abcd");
+        assert!(mgr.sessions.contains(5));
+
+        // The inbound session of a deleted peer is closed, found by the public key.
+        DelNodeReq::new(peer).handle(&mut mgr);
+        assert!(!mgr.sessions.contains(2));
+        assert!(mgr.sessions.contains(5));
+        assert!(mgr.peer_keys.is_empty());
+    }
+
+    #[test]
+    fn bounded_known_addrs() {
+        let mut mgr = NodesManager::default();
+        mgr.max_known_addrs = 4;
+        mgr.max_addrs_per_session = 2;
+        AddNodeReq::new_persistent("10.0.0.1:4000".parse().unwrap()).handle(&mut mgr);
+        for (session_id, addr) in &[
+            (11, "10.1.0.1:50011"),
+            (12, "10.1.0.2:50012"),
+            (13, "10.1.0.3:50013"),
+            (14, "10.1.0.1:50014"),
+        ] {
+            SessionOpenReq::new(
+                addr.parse().unwrap(),
+                *session_id,
+                SessionType::Server,
+                None,
+            )
+            .handle(&mut mgr);
+        }
+
+        // Limited per IP, across the sessions on it.
+        AddNodeReq::new_discovered("10.0.0.2:4000".parse().unwrap(), 11).handle(&mut mgr);
+        AddNodeReq::new_discovered("10.0.0.3:4000".parse().unwrap(), 14).handle(&mut mgr);
+        AddNodeReq::new_discovered("10.0.0.4:4000".parse().unwrap(), 11).handle(&mut mgr);
+        assert_eq!(mgr.known_addrs.len(), 3);
+        assert!(!is_known(&mgr, "10.0.0.4:4000"));
+
+        // Ignored from an unknown session.
+        AddNodeReq::new_discovered("10.0.0.4:4000".parse().unwrap(), 99).handle(&mut mgr);
+        assert!(!is_known(&mgr, "10.0.0.4:4000"));
+
+        // The known addresses do not count.
+        AddNodeReq::new_discovered("10.0.0.2:4000".parse().unwrap(), 11).handle(&mut mgr);
+        AddNodeReq::new_discovered("10.0.0.4:4000".parse().unwrap(), 12).handle(&mut mgr);
+        assert_eq!(mgr.known_addrs.len(), 4);
+
+        // Full, evict the entry with a lower score.
+        SessionOpenReq::new(
+            "10.0.0.3:4000".parse().unwrap(),
+            1,
+            SessionType::Client,
+            None,
+        )
+        .handle(&mut mgr);
+        UpdateScoreReq::new(1, ScoreEvent::MalformedFrame).handle(&mut mgr);
+        DelConnectedNodeReq::new(1).handle(&mut mgr);
+        AddNodeReq::new_discovered("10.0.0.5:4000".parse().unwrap(), 12).handle(&mut mgr);
+        assert_eq!(mgr.known_addrs.len(), 4);
+        assert!(!is_known(&mgr, "10.0.0.3:4000"));
+        assert!(is_known(&mgr, "10.0.0.5:4000"));
+
+        // No entry has a lower score than a new one, and the persistent entry is never
+        // evicted, even with the lowest score.
+        for _ in 0..10 {
+            DialFailedReq::new("10.0.0.1:4000".parse().unwrap()).handle(&mut mgr);
+        }
+        AddNodeReq::new_discovered("10.0.0.6:4000".parse().unwrap(), 13).handle(&mut mgr);
+        assert_eq!(mgr.known_addrs.len(), 4);
+        assert!(is_known(&mgr, "10.0.0.1:4000"));
+        assert!(!is_known(&mgr, "10.0.0.6:4000"));
+
+        // A new persistent entry is always added.
+        AddNodeReq::new_persistent("10.0.0.7:4000".parse().unwrap()).handle(&mut mgr);
+        assert_eq!(mgr.known_addrs.len(), 4);
+        assert!(is_known(&mgr, "10.0.0.7:4000"));
+
+        // The counts are forgotten after the window.
+        mgr.prune_discovered_counts(Instant::now());
+        assert_eq!(mgr.discovered_counts.len(), 2);
+        mgr.prune_discovered_counts(Instant::now() + DISCOVERED_ADDRS_WINDOW);
+        assert!(mgr.discovered_counts.is_empty());
+    }
+
+    #[test]
+    fn negotiate_compression() {
+        let mut mgr = NodesManager::default();
+        mgr.compression = vec![Compression::Zstd, Compression::Lz4];
+        mgr.enable_checksum = true;
+        for id in 1..4 {
+            let addr = format!("10.0.0.{}:50000", id).parse().unwrap();
+            SessionOpenReq::new(addr, id, SessionType::Server, None).handle(&mut mgr);
+        }
+
+        let capabilities = Capabilities {
+            compression: vec![Compression::Lz4, Compression::Zstd],
+            checksum: true,
+            fragment: true,
+        };
+        PeerCapabilitiesReq::new(1, capabilities).handle(&mut mgr);
+        let capabilities = Capabilities {
+            compression: vec![Compression::Snappy],
+            checksum: false,
+            fragment: false,
+        };
+        PeerCapabilitiesReq::new(2, capabilities).handle(&mut mgr);
+        let negotiated = |mgr: &NodesManager, id| {
+            let session = mgr.sessions.get(id).unwrap();
+            (session.compression, session.checksum, session.fragment)
+        };
+        assert_eq!(negotiated(&mgr, 1), (Some(Compression::Zstd), true, true));
+        assert_eq!(negotiated(&mgr, 2), (None, false, false));
+        // Old nodes send no capabilities, so nothing is compressed, checksummed or split.
+        assert_eq!(negotiated(&mgr, 3), (None, false, false));
+    }
+
+    #[test]
+    fn queue_fragments() {
+        let mut mgr = NodesManager::default();
+        mgr.fragment_size = 100;
+        for id in 1..3 {
+            let addr = format!("10.0.0.{}:50000", id).parse().unwrap();
+            SessionOpenReq::new(addr, id, SessionType::Server, None).handle(&mut mgr);
+        }
+        let capabilities = Capabilities {
+            compression: Vec::new(),
+            checksum: true,
+            fragment: true,
+        };
+        PeerCapabilitiesReq::new(1, capabilities).handle(&mut mgr);
+
+        // Short messages are not split.
+        mgr.send_transfer(vec![1, 2], "key".to_owned(), vec![1; 100]);
+        assert!(mgr.fragment_queues.is_empty());
+
+        let body: Vec<u8> = (0..250).map(|i| i as u8).collect();
+        mgr.send_transfer(vec![1, 2], "key".to_owned(), body.clone());
+        let queue = &mgr.fragment_queues[&1];
+        assert_eq!(queue.frames.len(), 3);
+        assert_eq!(
+            queue.bytes,
+            queue
+                .frames
+                .iter()
+                .map(|(frame, _)| frame.len())
+                .sum::<usize>()
+        );
+        let lasts: Vec<bool> = queue.frames.iter().map(|(_, last)| *last).collect();
+        assert_eq!(lasts, vec![false, false, true]);
+        // Counted once the fragments are sent.
+        assert_eq!(mgr.sessions.get(1).unwrap().bytes_sent, 0);
+        // The peer without the capability gets the whole message.
+        assert!(!mgr.fragment_queues.contains_key(&2));
+
+        let now = Instant::now();
+        let mut reassembler = Reassembler::default();
+        let mut messages = Vec::new();
+        for (frame, _) in mgr.fragment_queues[&1].frames.iter() {
+            let mut buf = BytesMut::from(&frame[..]);
+            let msg = decode_network_message(&mut buf).unwrap().unwrap();
+            assert_ne!(msg.flags & FLAG_CHECKSUM, 0);
+            messages.extend(reassembler.insert(1, msg, now).unwrap());
+        }
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].key, "key");
+        assert_eq!(messages[0].body, body);
+
+        // A message is dropped to its last fragment once a fragment is lost.
+        mgr.send_transfer(vec![1], "key".to_owned(), body.clone());
+        let queue = mgr.fragment_queues.get_mut(&1).unwrap();
+        assert_eq!(queue.frames.len(), 6);
+        let (_, last) = queue.pop().unwrap();
+        assert_eq!(queue.drop_message(last), 2);
+        let lasts: Vec<bool> = queue.frames.iter().map(|(_, last)| *last).collect();
+        assert_eq!(lasts, vec![false, false, true]);
+        assert_eq!(
+            queue.bytes,
+            queue
+                .frames
+                .iter()
+                .map(|(frame, _)| frame.len())
+                .sum::<usize>()
+        );
+
+        DelConnectedNodeReq::new(1).handle(&mut mgr);
+        assert!(mgr.fragment_queues.is_empty());
+    }
+
+    #[test]
+    fn count_checksum_failures() {
+        let mut mgr = NodesManager::default();
+        let addr: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        AddNodeReq::new(addr).handle(&mut mgr);
+        SessionOpenReq::new(addr, 1, SessionType::Client, None).handle(&mut mgr);
+        let score = mgr.known_addrs[&RawAddr::from(addr)].score;
+
+        UpdateScoreReq::new(1, ScoreEvent::BadChecksum).handle(&mut mgr);
+        UpdateScoreReq::new(1, ScoreEvent::BadChecksum).handle(&mut mgr);
+        assert_eq!(mgr.sessions.get(1).unwrap().checksum_failures, 2);
+        assert_eq!(mgr.metrics.bad_checksums, 2);
+        assert!(mgr.known_addrs[&RawAddr::from(addr)].score < score);
+    }
+
+    #[test]
+    fn score_inbound_sessions() {
+        let mut mgr = NodesManager::default();
+        let addr: SocketAddr = "10.0.0.1:50001".parse().unwrap();
+        SessionOpenReq::new(addr, 1, SessionType::Server, None).handle(&mut mgr);
+        let score = mgr.session_score(&addr.ip());
+
+        UpdateScoreReq::new(1, ScoreEvent::MalformedFrame).handle(&mut mgr);
+        assert!(mgr.session_score(&addr.ip()) < score);
+    }
+
+    #[test]
+    fn learn_observed_addr() {
+        let mut mgr = NodesManager::default();
+        mgr.learn_external_addr = true;
+        mgr.listen_addrs = vec!["/ip4/0.0.0.0/tcp/4000".parse().unwrap()];
+        let observe = |mgr: &mut NodesManager, session_id: SessionId, ty, addr: &str| {
+            let addr = addr.parse().unwrap();
+            SessionOpenReq::new(addr, session_id, ty, None).handle(mgr);
+            let observed = "/ip4/1.2.3.4/tcp/50000".parse().unwrap();
+            AddObservedAddrReq::new(session_id, observed).handle(mgr);
+        };
+
+        // Two of the peers share a /16, and inbound peers don't count.
+        observe(&mut mgr, 1, SessionType::Client, "10.0.0.1:4000");
+        observe(&mut mgr, 2, SessionType::Client, "10.0.0.2:4000");
+        observe(&mut mgr, 3, SessionType::Client, "10.1.0.1:4000");
+        observe(&mut mgr, 4, SessionType::Server, "10.2.0.1:50004");
+        assert!(mgr.advertise_addrs().is_empty());
+
+        observe(&mut mgr, 5, SessionType::Client, "10.3.0.1:4000");
+        let expected: Multiaddr = "/ip4/1.2.3.4/tcp/4000".parse().unwrap();
+        assert_eq!(mgr.advertise_addrs(), vec![expected]);
+
+        DelConnectedNodeReq::new(5).handle(&mut mgr);
+        assert!(mgr.advertise_addrs().is_empty());
+    }
+
+    #[test]
+    fn reload_config_peers() {
+        let tmp_file: NamedTempFile = NamedTempFile::new().unwrap();
+        let path = tmp_file.path().to_str().unwrap();
+        fs::write(
+            path,
+            r#"
+            port = 4000
+            [[peers]]
+                ip = "10.0.0.1"
+                port = 4001
+            [[peers]]
+                ip = "10.0.0.2"
+                port = 4002
+            "#,
+        )
+        .unwrap();
+        let config = NetConfig::new(path);
+        let mut mgr = NodesManager::from_config(config.clone());
+        let mut watcher = ConfigWatcher::new(path, config, mgr.client());
+
+        fs::write(
+            path,
+            r#"
+            port = 4000
+            [[peers]]
+                ip = "10.0.0.2"
+                port = 4002
+            [[peers]]
+                ip = "10.0.0.3"
+                port = 4003
+                reserved = true
+            "#,
+        )
+        .unwrap();
+        watcher.reload();
+        let messages: Vec<_> = mgr.nodes_manager_service_receiver.try_iter().collect();
+        assert_eq!(messages.len(), 2);
+        for msg in messages {
+            msg.handle(&mut mgr);
+        }
+
+        let raw = |addr: &str| RawAddr::from(addr.parse::<SocketAddr>().unwrap());
+        assert!(!mgr.is_persistent(&raw("10.0.0.1:4001")));
+        assert!(mgr.is_persistent(&raw("10.0.0.2:4002")));
+        assert!(mgr.is_reserved(&raw("10.0.0.3:4003")));
+        assert!(!mgr.is_reserved(&raw("10.0.0.2:4002")));
+    }
+
+    #[test]
+    fn shutdown() {
+        let dir = tempdir().unwrap();
+        let config = config_from_str(&format!(
+            "port = 4000\ndata_dir = \"{}\"\n",
+            dir.path().display()
+        ));
+        let mut mgr = NodesManager::from_config(config);
+        AddNodeReq::new("10.0.0.1:4000".parse().unwrap()).handle(&mut mgr);
+
+        // The loop returns once the address book is saved.
+        mgr.client().shutdown(ShutdownReq::new());
+        mgr.run();
+        assert!(dir.path().join(ADDRESS_BOOK_FILE).exists());
+    }
+}
@@ -0,0 +1,121 @@
+use crate::node_manager::{
+    AddObservedAddrReq, NodesManagerClient, ProtocolCloseReq, ProtocolOpenReq,
+};
+use log::{debug, warn};
+use p2p::{
+    context::{ServiceContext, SessionContext},
+    multiaddr::Multiaddr,
+    traits::{ProtocolMeta, ServiceProtocol},
+    ProtocolId,
+};
+use std::str;
+use tokio::codec::length_delimited::LengthDelimitedCodec;
+
+pub const IDENTIFY_PROTOCOL_ID: ProtocolId = 2;
+
+/// Tell every connected peer the address we observe for it, and collect the
+/// addresses peers observe for us, so a node behind NAT can learn its external address.
+/// Nothing is sent or collected unless `learn_external_addr` is on.
+pub struct IdentifyProtocolMeta {
+    id: ProtocolId,
+    nodes_mgr_client: NodesManagerClient,
+    learn_external_addr: bool,
+}
+
+impl IdentifyProtocolMeta {
+    pub fn new(
+        id: ProtocolId,
+        nodes_mgr_client: NodesManagerClient,
+        learn_external_addr: bool,
+    ) -> Self {
+        IdentifyProtocolMeta {
+            id,
+            nodes_mgr_client,
+            learn_external_addr,
+        }
+    }
+}
+
+impl ProtocolMeta<LengthDelimitedCodec> for IdentifyProtocolMeta {
+    fn id(&self) -> ProtocolId {
+        self.id
+    }
+
+    fn codec(&self) -> LengthDelimitedCodec {
+        LengthDelimitedCodec::new()
+    }
+
+    fn service_handle(&self) -> Option<Box<dyn ServiceProtocol + Send + 'static>> {
+        let handle = Box::new(IdentifyProtocol {
+            proto_id: self.id,
+            nodes_mgr_client: self.nodes_mgr_client.clone(),
+            learn_external_addr: self.learn_external_addr,
+        });
+        Some(handle)
+    }
+}
+
+struct IdentifyProtocol {
+    proto_id: ProtocolId,
+    nodes_mgr_client: NodesManagerClient,
+    learn_external_addr: bool,
+}
+
+impl ServiceProtocol for IdentifyProtocol {
+    fn init(&mut self, _control: &mut ServiceContext) {}
+
+    fn connected(&mut self, control: &mut ServiceContext, session: &SessionContext, version: &str) {
+        debug!(
+            "[identify] open on session [{}], observed address: [{}]",
+            session.id, session.address
+        );
+        self.nodes_mgr_client.protocol_open(ProtocolOpenReq::new(
+            session.id,
+            self.proto_id,
+            version.to_owned(),
+        ));
+        if !self.learn_external_addr {
+            return;
+        }
+        let observed = session.address.to_string().into_bytes();
+        let mut ctrl = control.control().clone();
+        if let Err(err) = ctrl.send_message(Some(vec![session.id]), self.proto_id, observed) {
+            warn!(
+                "[identify] Send observed address to session [{}] failed: {:?}",
+                session.id, err
+            );
+        }
+    }
+
+    fn disconnected(&mut self, _control: &mut ServiceContext, session: &SessionContext) {
+        debug!("[identify] close on session [{}]", session.id);
+        self.nodes_mgr_client
+            .protocol_close(ProtocolCloseReq::new(session.id, self.proto_id));
+    }
+
+    fn received(&mut self, _control: &mut ServiceContext, session: &SessionContext, data: Vec<u8>) {
+        if !self.learn_external_addr {
+            return;
+        }
+        let observed = str::from_utf8(&data)
+            .ok()
+            .and_then(|addr| addr.parse::<Multiaddr>().ok());
+
+        match observed {
+            Some(addr) => {
+                debug!(
+                    "[identify] Session [{}] observes us on {}",
+                    session.id, addr
+                );
+                self.nodes_mgr_client
+                    .add_observed_addr(AddObservedAddrReq::new(session.id, addr));
+            }
+            None => {
+                warn!(
+                    "[identify] Receive invalid observed address from session [{}]",
+                    session.id
+                );
+            }
+        }
+    }
+}
@@ -1,16 +1,16 @@
 use crate::node_manager::{
-    AddConnectedNodeReq, DelConnectedNodeReq, DelNodeReq, NodesManagerClient,
+    AddConnectedNodeReq, DelConnectedNodeReq, DialFailedReq, NodesManagerClient, SessionOpenReq,
 };
-use log::{debug, warn};
+use log::{debug, error, warn};
 use p2p::{
     context::ServiceContext,
     error,
     service::{ServiceError, ServiceEvent},
     traits::ServiceHandle,
     utils::multiaddr_to_socketaddr,
-    SessionType,
 };
 
+pub mod identify;
 pub mod node_discovery;
 pub mod transfer;
 
@@ -40,18 +40,18 @@ impl ServiceHandle for SHandle {
                         debug!("[handle_error] Connected to the same node : {:?}", address);
                     }
                     _ => {
-                        //FIXME: Using score for deleting a node from known nodes
-                        let req = DelNodeReq::new(address);
-                        self.nodes_mgr_client.del_node(req);
-                        warn!("[handle_error] Error in {:?} : {:?}, delete this address from nodes manager",
-                              address, error);
+                        let req = DialFailedReq::new(address);
+                        self.nodes_mgr_client.dial_failed(req);
+                        warn!(
+                            "[handle_error] Error in {:?} : {:?}, lower the score of this address",
+                            address, error
+                        );
                     }
                 }
             }
             ServiceError::ListenError { address, error } => {
-                let address = multiaddr_to_socketaddr(&address).unwrap();
-                warn!(
-                    "[handle_error] Listen error on {:?}, error info: {:?}",
+                error!(
+                    "[handle_error] Listen error on {}, error info: {:?}",
                     address, error
                 );
             }
@@ -80,10 +80,11 @@ impl ServiceHandle for SHandle {
                 let address = multiaddr_to_socketaddr(&address).unwrap();
                 debug!("[handle_event] Service open on : {:?}, session id: {:?}, ty: {:?}, public_key: {:?}",
                        address, id, ty, public_key);
-                if ty == SessionType::Client {
-                    let req = AddConnectedNodeReq::new(address, id);
-                    self.nodes_mgr_client.add_connected_node(req);
-                }
+                let public_key = public_key.map(|key| hex::encode(key.inner_ref()));
+                // The nodes manager checks the allowlist and the ban list, and closes
+                // the session if it is refused.
+                let req = SessionOpenReq::new(address, id, ty, public_key);
+                self.nodes_mgr_client.session_open(req);
             }
             ServiceEvent::SessionClose { id } => {
                 let req = DelConnectedNodeReq::new(id);
@@ -1,11 +1,17 @@
-use crate::node_manager::{AddNodeReq, GetRandomNodesReq, NodesManagerClient};
+use crate::address_book::DEFAULT_SCORE;
+use crate::node_manager::{
+    AddNodeReq, GetAdvertiseAddrsReq, GetRandomNodesReq, MisbehaveReq, NodesManagerClient,
+    ProtocolCloseReq, ProtocolOpenReq,
+};
+use crate::peer_score::Misbehavior;
 use crossbeam_channel;
 use crossbeam_channel::unbounded;
-use discovery::{AddressManager, Direction, Discovery, DiscoveryHandle, Substream};
+use discovery::{AddressManager, Direction, Discovery, Substream};
 use fnv::FnvHashMap;
 use futures::{
     prelude::*,
     sync::mpsc::{channel, Sender},
+    sync::oneshot,
 };
 use log::{debug, warn};
 use p2p::{
@@ -20,25 +26,65 @@ use tokio::codec::length_delimited::LengthDelimitedCodec;
 #[derive(Clone, Debug)]
 pub struct NodesAddressManager {
     pub nodes_mgr_client: NodesManagerClient,
+    // The session the addresses come from, so the nodes manager can limit each session.
+    session_id: Option<SessionId>,
 }
 
 impl NodesAddressManager {
     pub fn new(nodes_mgr_client: NodesManagerClient) -> Self {
-        NodesAddressManager { nodes_mgr_client }
+        NodesAddressManager {
+            nodes_mgr_client,
+            session_id: None,
+        }
+    }
+
+    pub fn for_session(&self, session_id: SessionId) -> Self {
+        NodesAddressManager {
+            nodes_mgr_client: self.nodes_mgr_client.clone(),
+            session_id: Some(session_id),
+        }
     }
 }
 
 impl AddressManager for NodesAddressManager {
     fn add_new(&mut self, addr: Multiaddr) {
-        let address = multiaddr_to_socketaddr(&addr).unwrap();
-        let req = AddNodeReq::new(address);
+        let address = match multiaddr_to_socketaddr(&addr) {
+            Some(address) => address,
+            None => {
+                warn!("[add_new] Ignore invalid address {:?}", addr);
+                return;
+            }
+        };
+        let req = match self.session_id {
+            Some(session_id) => AddNodeReq::new_discovered(address, session_id),
+            None => AddNodeReq::new(address),
+        };
         self.nodes_mgr_client.add_node(req);
 
         debug!("[add_new] Add node {:?} to manager", address);
     }
 
-    fn misbehave(&mut self, _addr: Multiaddr, _ty: u64) -> i32 {
-        unimplemented!()
+    fn misbehave(&mut self, addr: Multiaddr, ty: u64) -> i32 {
+        let address = match multiaddr_to_socketaddr(&addr) {
+            Some(address) => address,
+            None => {
+                warn!("[misbehave] Invalid address {:?}", addr);
+                return DEFAULT_SCORE;
+            }
+        };
+        let (tx, rx) = unbounded();
+
+        let req = MisbehaveReq::new(address, Misbehavior::from_code(ty), tx);
+        self.nodes_mgr_client.misbehave(req);
+
+        let score = rx.recv().unwrap_or(DEFAULT_SCORE);
+
+        debug!(
+            "[misbehave] Node {:?} misbehave {}, score: {}",
+            address, ty, score
+        );
+
+        score
     }
 
     fn get_random(&mut self, n: usize) -> Vec<Multiaddr> {
@@ -57,55 +103,82 @@ impl AddressManager for NodesAddressManager {
     }
 }
 
+// Each session runs its own discovery, tagged with the session id, so the addresses
+// gossiped by a session can be counted and limited.
 pub struct DiscoveryProtocol {
     id: usize,
-    discovery: Option<Discovery<NodesAddressManager>>,
-    discovery_handle: DiscoveryHandle,
-    discovery_senders: FnvHashMap<SessionId, Sender<Vec<u8>>>,
+    addr_mgr: NodesAddressManager,
+    // The channel to the discovery of each session, and the sender to stop it.
+    discovery_senders: FnvHashMap<SessionId, (Sender<Vec<u8>>, oneshot::Sender<()>)>,
+    nodes_mgr_client: NodesManagerClient,
+}
+
+impl DiscoveryProtocol {
+    // Get the addresses advertised to other nodes, fall back to the listen addresses.
+    fn advertise_addrs(&self, control: &ServiceContext) -> Vec<Multiaddr> {
+        let (tx, rx) = unbounded();
+        self.nodes_mgr_client
+            .get_advertise_addrs(GetAdvertiseAddrsReq::new(tx));
+
+        match rx.recv() {
+            Ok(ref addrs) if !addrs.is_empty() => addrs.clone(),
+            _ => control.listens().to_vec(),
+        }
+    }
 }
 
 impl ServiceProtocol for DiscoveryProtocol {
-    fn init(&mut self, control: &mut ServiceContext) {
+    fn init(&mut self, _control: &mut ServiceContext) {
         debug!("protocol [discovery({})]: init", self.id);
-
-        let discovery_task = self
-            .discovery
-            .take()
-            .map(|discovery| {
-                debug!("Start discovery future_task");
-                discovery
-                    .for_each(|()| {
-                        debug!("discovery.for_each()");
-                        Ok(())
-                    })
-                    .map_err(|err| {
-                        warn!("discovery stream error: {:?}", err);
-                    })
-                    .then(|_| {
-                        warn!("End of discovery");
-                        Ok(())
-                    })
-            })
-            .unwrap();
-        let _ = control.future_task(discovery_task);
     }
 
     // open a discovery protocol session?
-    fn connected(&mut self, control: &mut ServiceContext, session: &SessionContext, _: &str) {
+    fn connected(&mut self, control: &mut ServiceContext, session: &SessionContext, version: &str) {
         debug!(
             "protocol [discovery] open session [{}], address: [{}], type: [{:?}]",
             session.id, session.address, session.ty
         );
+        self.nodes_mgr_client.protocol_open(ProtocolOpenReq::new(
+            session.id,
+            self.id,
+            version.to_owned(),
+        ));
 
-        debug!("listen list: {:?}", control.listens());
+        let advertise_addrs = self.advertise_addrs(control);
+        debug!(
+            "listen list: {:?}, advertise list: {:?}",
+            control.listens(),
+            advertise_addrs
+        );
         let direction = if session.ty == SessionType::Server {
             Direction::Inbound
         } else {
             Direction::Outbound
         };
 
+        let discovery = Discovery::new(self.addr_mgr.for_session(session.id));
+        let mut discovery_handle = discovery.handle();
+        let (stop_sender, stop_receiver) = oneshot::channel();
+        let session_id = session.id;
+        debug!("Start discovery future_task for session {}", session_id);
+        let discovery_task = discovery
+            .for_each(|()| {
+                debug!("discovery.for_each()");
+                Ok(())
+            })
+            .map_err(|err| {
+                warn!("discovery stream error: {:?}", err);
+            })
+            .select(stop_receiver.map_err(|_| ()))
+            .then(move |_| {
+                debug!("End of discovery for session {}", session_id);
+                Ok(())
+            });
+        let _ = control.future_task(discovery_task);
+
         let (sender, receiver) = channel(8);
-        self.discovery_senders.insert(session.id, sender);
+        self.discovery_senders
+            .insert(session.id, (sender, stop_sender));
 
         let substream = Substream::new(
             &session.address,
@@ -114,10 +187,10 @@ impl ServiceProtocol for DiscoveryProtocol {
             session.id,
             receiver,
             control.control().clone(),
-            control.listens(),
+            &advertise_addrs,
         );
 
-        match self.discovery_handle.substream_sender.try_send(substream) {
+        match discovery_handle.substream_sender.try_send(substream) {
             Ok(_) => {
                 debug!("Send substream success");
             }
@@ -128,13 +201,16 @@ impl ServiceProtocol for DiscoveryProtocol {
     }
 
     fn disconnected(&mut self, _control: &mut ServiceContext, session: &SessionContext) {
+        // Dropping the stop sender ends the discovery of the session.
         self.discovery_senders.remove(&session.id);
+        self.nodes_mgr_client
+            .protocol_close(ProtocolCloseReq::new(session.id, self.id));
         debug!("protocol [discovery] close on session [{}]", session.id);
     }
 
     fn received(&mut self, _control: &mut ServiceContext, session: &SessionContext, data: Vec<u8>) {
         debug!("[received message]: length={}", data.len());
-        if let Some(ref mut sender) = self.discovery_senders.get_mut(&session.id) {
+        if let Some((ref mut sender, _)) = self.discovery_senders.get_mut(&session.id) {
             if let Err(err) = sender.try_send(data) {
                 if err.is_full() {
                     warn!("channel is full");
@@ -148,6 +224,8 @@ impl ServiceProtocol for DiscoveryProtocol {
     }
 }
 
+pub const DISCOVERY_PROTOCOL_ID: ProtocolId = 0;
+
 pub struct DiscoveryProtocolMeta {
     pub id: ProtocolId,
     pub addr_mgr: NodesAddressManager,
@@ -169,14 +247,11 @@ impl ProtocolMeta<LengthDelimitedCodec> for DiscoveryProtocolMeta {
     }
 
     fn service_handle(&self) -> Option<Box<dyn ServiceProtocol + Send + 'static>> {
-        let discovery = Discovery::new(self.addr_mgr.clone());
-        let discovery_handle = discovery.handle();
-
         let handle = Box::new(DiscoveryProtocol {
             id: self.id,
-            discovery: Some(discovery),
-            discovery_handle,
+            addr_mgr: self.addr_mgr.clone(),
             discovery_senders: FnvHashMap::default(),
+            nodes_mgr_client: self.addr_mgr.nodes_mgr_client.clone(),
         });
 
         Some(handle)
@@ -1,23 +1,62 @@
-use crate::citaprotocol::network_message_to_pubsub_message;
+use crate::allowlist::Allowlist;
+use crate::citaprotocol::{decode_network_message, DecodeError, NetworkMessage, FLAG_FRAGMENT};
+use crate::compression::{decode_message, Capabilities, CAPABILITIES_KEY};
+use crate::fragment::{Reassembler, ReassemblyError};
 use crate::network::{NetworkClient, RemoteMessage};
+use crate::node_manager::{
+    MessageReceivedReq, NodesManagerClient, PeerCapabilitiesReq, ProtocolCloseReq, ProtocolOpenReq,
+    UpdateScoreReq,
+};
+use crate::peer_score::ScoreEvent;
 use bytes::BytesMut;
 use libproto::{Message as ProtoMessage, TryFrom, TryInto};
-use log::info;
+use log::{info, warn};
 use p2p::{
     context::{ServiceContext, SessionContext},
     traits::{ProtocolMeta, ServiceProtocol},
     ProtocolId, SessionId,
 };
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 use tokio::codec::length_delimited::LengthDelimitedCodec;
 
+pub const TRANSFER_PROTOCOL_ID: ProtocolId = 1;
+/// The first version of the protocol.
+pub const TRANSFER_VERSION_LEGACY: &str = "0.0.1";
+/// Both sides send the capabilities frame once the protocol is open, to negotiate the
+/// compression, the checksums and the fragments.
+pub const TRANSFER_VERSION_CAPABILITIES: &str = "0.0.2";
+/// The received messages are counted here and reported to the nodes manager in batches,
+/// about this often, and when the session is closed.
+pub const REPORT_RECEIVED_INTERVAL: Duration = Duration::from_secs(1);
+/// A session is closed after this number of frames which can not be decoded, the stream
+/// is not worth resynchronizing any more.
+pub const MAX_DECODE_ERRORS: usize = 10;
+
 pub struct TransferProtocolMeta {
     id: ProtocolId,
     network_client: NetworkClient,
+    nodes_mgr_client: NodesManagerClient,
+    reassembler: Reassembler,
+    allowlist: Arc<RwLock<Allowlist>>,
 }
 
 impl TransferProtocolMeta {
-    pub fn new(id: ProtocolId, network_client: NetworkClient) -> Self {
-        TransferProtocolMeta { id, network_client }
+    pub fn new(
+        id: ProtocolId,
+        network_client: NetworkClient,
+        nodes_mgr_client: NodesManagerClient,
+        reassembler: Reassembler,
+        allowlist: Arc<RwLock<Allowlist>>,
+    ) -> Self {
+        TransferProtocolMeta {
+            id,
+            network_client,
+            nodes_mgr_client,
+            reassembler,
+            allowlist,
+        }
     }
 }
 
@@ -28,11 +67,23 @@ impl ProtocolMeta<LengthDelimitedCodec> for TransferProtocolMeta {
     fn codec(&self) -> LengthDelimitedCodec {
         LengthDelimitedCodec::new()
     }
+    fn support_versions(&self) -> Vec<String> {
+        vec![
+            TRANSFER_VERSION_LEGACY.to_owned(),
+            TRANSFER_VERSION_CAPABILITIES.to_owned(),
+        ]
+    }
     fn service_handle(&self) -> Option<Box<dyn ServiceProtocol + Send + 'static>> {
         let handle = Box::new(TransferProtocol {
             proto_id: self.id,
-            connected_session_ids: Vec::default(),
             network_client: self.network_client.clone(),
+            nodes_mgr_client: self.nodes_mgr_client.clone(),
+            reassembler: self.reassembler.clone(),
+            allowlist: Arc::clone(&self.allowlist),
+            rejected_sessions: HashSet::new(),
+            received: HashMap::new(),
+            reported_at: Instant::now(),
+            decode_errors: HashMap::new(),
         });
         Some(handle)
     }
@@ -40,8 +91,93 @@ impl ProtocolMeta<LengthDelimitedCodec> for TransferProtocolMeta {
 
 struct TransferProtocol {
     proto_id: ProtocolId,
-    connected_session_ids: Vec<SessionId>,
     network_client: NetworkClient,
+    nodes_mgr_client: NodesManagerClient,
+    reassembler: Reassembler,
+    allowlist: Arc<RwLock<Allowlist>>,
+    // Sessions not allowed, which are being closed. Their messages are dropped.
+    rejected_sessions: HashSet<SessionId>,
+    // Bytes and messages received on each session and not reported yet.
+    received: HashMap<SessionId, (usize, usize)>,
+    reported_at: Instant,
+    // Number of frames failed to decode on each session.
+    decode_errors: HashMap<SessionId, usize>,
+}
+
+impl TransferProtocol {
+    fn count_received(&mut self, session_id: SessionId, bytes: usize) {
+        let counts = self.received.entry(session_id).or_insert((0, 0));
+        counts.0 += bytes;
+        counts.1 += 1;
+
+        let now = Instant::now();
+        if now.duration_since(self.reported_at) >= REPORT_RECEIVED_INTERVAL {
+            self.reported_at = now;
+            for (session_id, (bytes, messages)) in self.received.drain() {
+                let req = MessageReceivedReq::new(session_id, bytes, messages);
+                self.nodes_mgr_client.message_received(req);
+            }
+        }
+    }
+
+    // Score the peer for a frame which can not be decoded, and close the session once
+    // it sends too many of them.
+    fn decode_failed(
+        &mut self,
+        env: &mut ServiceContext,
+        session_id: SessionId,
+        event: ScoreEvent,
+    ) {
+        let req = UpdateScoreReq::new(session_id, event);
+        self.nodes_mgr_client.update_score(req);
+
+        let count = self.decode_errors.entry(session_id).or_insert(0);
+        *count += 1;
+        if *count >= MAX_DECODE_ERRORS {
+            warn!(
+                "[received] Disconnect session [{}], {} frames failed to decode",
+                session_id, count
+            );
+            self.rejected_sessions.insert(session_id);
+            if let Err(err) = env.control().clone().disconnect(session_id) {
+                warn!(
+                    "[received] Disconnect session {} failed : {:?}",
+                    session_id, err
+                );
+            }
+        }
+    }
+
+    // Pass a fragment to the reassembler, and return the message once it is finished.
+    fn reassemble(
+        &mut self,
+        session_id: SessionId,
+        msg: NetworkMessage,
+    ) -> Result<Option<NetworkMessage>, String> {
+        if msg.flags & FLAG_FRAGMENT == 0 {
+            return Ok(Some(msg));
+        }
+        let now = Instant::now();
+        let expired = self.reassembler.expire(now);
+        if expired > 0 {
+            warn!(
+                "[received] Drop {} fragmented messages not finished in time",
+                expired
+            );
+        }
+        match self.reassembler.insert(session_id, msg, now) {
+            // Not the fault of the peer, drop the fragment without scoring it.
+            Err(ReassemblyError::Full) => {
+                warn!(
+                    "[received] Drop a fragment from session [{}], {}",
+                    session_id,
+                    ReassemblyError::Full
+                );
+                Ok(None)
+            }
+            result => result.map_err(|err| err.to_string()),
+        }
+    }
 }
 
 impl ServiceProtocol for TransferProtocol {
@@ -57,21 +193,33 @@ impl ServiceProtocol for TransferProtocol {
             "[connected] proto id [{}] open on session [{}], address: [{}], type: [{:?}], version: {}",
             self.proto_id, session.id, session.address, session.ty, version
         );
-        self.connected_session_ids.push(session.id);
-        info!(
-            "[connected] connected sessions: {:?}",
-            self.connected_session_ids
-        );
+        let public_key = session
+            .remote_pubkey
+            .as_ref()
+            .map(|key| hex::encode(key.inner_ref()));
+        if !self
+            .allowlist
+            .read()
+            .unwrap()
+            .is_allowed(public_key.as_ref().map(String::as_str))
+        {
+            self.rejected_sessions.insert(session.id);
+            return;
+        }
+        let req = ProtocolOpenReq::new(session.id, self.proto_id, version.to_owned());
+        self.nodes_mgr_client.protocol_open(req);
     }
 
     fn disconnected(&mut self, _control: &mut ServiceContext, session: &SessionContext) {
-        let new_list = self
-            .connected_session_ids
-            .iter()
-            .filter(|&id| id != &session.id)
-            .cloned()
-            .collect();
-        self.connected_session_ids = new_list;
+        self.rejected_sessions.remove(&session.id);
+        self.decode_errors.remove(&session.id);
+        self.reassembler.remove_session(session.id);
+        if let Some((bytes, messages)) = self.received.remove(&session.id) {
+            let req = MessageReceivedReq::new(session.id, bytes, messages);
+            self.nodes_mgr_client.message_received(req);
+        }
+        let req = ProtocolCloseReq::new(session.id, self.proto_id);
+        self.nodes_mgr_client.protocol_close(req);
 
         info!(
             "[disconnected] proto id [{}] close on session [{}]",
@@ -79,13 +227,64 @@ impl ServiceProtocol for TransferProtocol {
         );
     }
 
-    fn received(&mut self, _env: &mut ServiceContext, session: &SessionContext, data: Vec<u8>) {
+    fn received(&mut self, env: &mut ServiceContext, session: &SessionContext, data: Vec<u8>) {
+        if self.rejected_sessions.contains(&session.id) {
+            return;
+        }
+
+        self.count_received(session.id, data.len());
+
         let mut data = BytesMut::from(data);
-        if let Some((key, message)) = network_message_to_pubsub_message(&mut data) {
-            let mut msg = ProtoMessage::try_from(&message).unwrap();
-            msg.set_origin(session.id as u32);
-            self.network_client
-                .handle_remote_message(RemoteMessage::new(key, msg.try_into().unwrap()));
+        // A transport message carries a whole frame.
+        let msg = match decode_network_message(&mut data) {
+            Ok(Some(msg)) => self.reassemble(session.id, msg),
+            Ok(None) if data.is_empty() => return,
+            Err(DecodeError::BadChecksum) => {
+                warn!(
+                    "[received] Drop a frame with a bad checksum from session [{}], address: [{}]",
+                    session.id, session.address
+                );
+                self.decode_failed(env, session.id, ScoreEvent::BadChecksum);
+                return;
+            }
+            Ok(None) => Err(DecodeError::Truncated.to_string()),
+            Err(err) => Err(err.to_string()),
+        };
+        let msg = match msg {
+            Ok(Some(msg)) => decode_message(msg, self.reassembler.max_message_len())
+                .map_err(|err| err.to_string()),
+            // A fragment of an unfinished message.
+            Ok(None) => return,
+            Err(reason) => Err(reason),
+        };
+        let msg = match msg {
+            Ok((ref key, ref body)) if key == CAPABILITIES_KEY => {
+                let req = PeerCapabilitiesReq::new(session.id, Capabilities::decode(body));
+                self.nodes_mgr_client.peer_capabilities(req);
+                return;
+            }
+            Ok((key, message)) => ProtoMessage::try_from(&message)
+                .ok()
+                .and_then(|mut msg| {
+                    msg.set_origin(session.id as u32);
+                    msg.try_into().ok()
+                })
+                .map(|data| (key, data))
+                .ok_or_else(|| "invalid message".to_owned()),
+            Err(reason) => Err(reason),
+        };
+        match msg {
+            Ok((key, data)) => {
+                self.network_client
+                    .handle_remote_message(RemoteMessage::new(key, data));
+            }
+            Err(reason) => {
+                warn!(
+                    "[received] Malformed frame from session [{}], address: [{}]: {}",
+                    session.id, session.address, reason
+                );
+                self.decode_failed(env, session.id, ScoreEvent::MalformedFrame);
+            }
         }
     }
 }
@@ -0,0 +1,167 @@
+use crate::address_book::AddrInfo;
+use rand::Rng;
+use std::time::Duration;
+
+pub const MAX_SCORE: i32 = 200;
+pub const MIN_SCORE: i32 = 0;
+/// Addresses whose score falls below this are removed from the known addresses.
+pub const EVICT_SCORE: i32 = 40;
+/// Discovered addresses are removed after this number of dial failures in a row.
+pub const DIAL_RETRY_BUDGET: u32 = 5;
+pub const DIAL_BACKOFF_BASE: Duration = Duration::from_secs(3);
+pub const DIAL_BACKOFF_MAX: Duration = Duration::from_secs(600);
+
+/// Misbehaviour reported by the discovery protocol. The discovery crate at the pinned
+/// revision reports it as a bare `u64`, which is decoded by `Misbehavior::from_code`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Misbehavior {
+    /// A second GetNodes on the same session.
+    DuplicateGetNodes,
+    /// A second Nodes message not answering a GetNodes.
+    DuplicateFirstNodes,
+    /// A Nodes message with too many items.
+    TooManyItems,
+    /// A node item with too many addresses.
+    TooManyAddresses,
+    /// A message which can not be decoded.
+    InvalidData,
+    /// A code this node does not know, treated as the lightest misbehaviour.
+    Unknown(u64),
+}
+
+impl Misbehavior {
+    pub fn from_code(code: u64) -> Self {
+        match code {
+            0 => Misbehavior::DuplicateGetNodes,
+            1 => Misbehavior::DuplicateFirstNodes,
+            2 => Misbehavior::TooManyItems,
+            3 => Misbehavior::TooManyAddresses,
+            4 => Misbehavior::InvalidData,
+            code => Misbehavior::Unknown(code),
+        }
+    }
+
+    /// The score lost for the misbehaviour.
+    pub fn penalty(self) -> i32 {
+        match self {
+            Misbehavior::DuplicateGetNodes | Misbehavior::DuplicateFirstNodes => 10,
+            Misbehavior::TooManyItems | Misbehavior::TooManyAddresses => 20,
+            Misbehavior::InvalidData => 50,
+            Misbehavior::Unknown(_) => 10,
+        }
+    }
+}
+
+/// Things a peer did that change its score.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScoreEvent {
+    DialSuccess,
+    DialFailure,
+    /// The session stays open for another uptime interval.
+    SessionUptime,
+    /// A frame from the peer can not be decoded.
+    MalformedFrame,
+    /// A frame from the peer fails the checksum, it may be corrupted on the way.
+    BadChecksum,
+    /// A sync request to the peer got no response in time.
+    SyncTimeout,
+    /// The peer responded a sync request with blocks.
+    UsefulSyncResponse,
+    /// The discovery protocol reported a misbehaviour of the peer.
+    Misbehave(Misbehavior),
+}
+
+impl ScoreEvent {
+    pub fn delta(self) -> i32 {
+        match self {
+            ScoreEvent::DialSuccess => 5,
+            ScoreEvent::DialFailure => -10,
+            ScoreEvent::SessionUptime => 1,
+            ScoreEvent::MalformedFrame => -20,
+            ScoreEvent::BadChecksum => -10,
+            ScoreEvent::SyncTimeout => -10,
+            ScoreEvent::UsefulSyncResponse => 2,
+            ScoreEvent::Misbehave(misbehavior) => -misbehavior.penalty(),
+        }
+    }
+}
+
+/// Apply the event to the address, and return the new score.
+pub fn update_score(info: &mut AddrInfo, event: ScoreEvent) -> i32 {
+    match event {
+        ScoreEvent::DialSuccess => info.connected(),
+        ScoreEvent::DialFailure => info.failure_count = info.failure_count.saturating_add(1),
+        _ => {}
+    }
+    info.score = (info.score + event.delta()).max(MIN_SCORE).min(MAX_SCORE);
+    info.score
+}
+
+pub fn should_evict(info: &AddrInfo) -> bool {
+    info.score < EVICT_SCORE
+}
+
+/// How long to wait before dialing again after `failure_count` failures in a row.
+/// The backoff doubles on each failure up to `DIAL_BACKOFF_MAX`, with up to 50% jitter
+/// added, so the nodes restarted together do not dial at the same time.
+pub fn dial_backoff<R: Rng>(failure_count: u32, rng: &mut R) -> Duration {
+    let exp = failure_count.saturating_sub(1).min(16);
+    let backoff = (DIAL_BACKOFF_BASE.as_secs() << exp).min(DIAL_BACKOFF_MAX.as_secs()) * 1000;
+    let jitter = rng.gen_range(0, backoff / 2 + 1);
+    Duration::from_millis(backoff + jitter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        dial_backoff, should_evict, update_score, ScoreEvent, DIAL_BACKOFF_BASE, DIAL_BACKOFF_MAX,
+        MAX_SCORE,
+    };
+    use crate::address_book::AddrInfo;
+    use rand::thread_rng;
+    use std::time::Duration;
+
+    #[test]
+    fn evict_after_dial_failures() {
+        let mut info = AddrInfo::default();
+        let mut failures = 0;
+        while !should_evict(&info) {
+            update_score(&mut info, ScoreEvent::DialFailure);
+            failures += 1;
+        }
+        assert_eq!(failures, 7);
+        assert_eq!(info.failure_count, 7);
+
+        update_score(&mut info, ScoreEvent::DialSuccess);
+        assert_eq!(info.failure_count, 0);
+        assert!(info.last_success.is_some());
+    }
+
+    #[test]
+    fn score_is_bounded() {
+        let mut info = AddrInfo::default();
+        for _ in 0..100 {
+            update_score(&mut info, ScoreEvent::UsefulSyncResponse);
+        }
+        assert_eq!(info.score, MAX_SCORE);
+
+        for _ in 0..100 {
+            update_score(&mut info, ScoreEvent::MalformedFrame);
+        }
+        assert_eq!(info.score, 0);
+    }
+
+    #[test]
+    fn exponential_backoff_with_jitter() {
+        let mut rng = thread_rng();
+        for failure_count in 1..30 {
+            let base =
+                (DIAL_BACKOFF_BASE * 2u32.pow((failure_count - 1).min(16))).min(DIAL_BACKOFF_MAX);
+            let backoff = dial_backoff(failure_count, &mut rng);
+            assert!(backoff >= base);
+            assert!(backoff <= base + base / 2);
+        }
+        assert!(dial_backoff(1, &mut rng) < Duration::from_secs(5));
+        assert!(dial_backoff(100, &mut rng) >= DIAL_BACKOFF_MAX);
+    }
+}
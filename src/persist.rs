@@ -0,0 +1,37 @@
+//! Helpers to save the network data files.
+
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Write `value` as toml to `path`. Write to a temporary file then rename it,
+/// so a crash never leaves a half written file.
+pub fn write_toml_file<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    let content =
+        toml::to_string(value).map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+    write_file(path, content.as_bytes())
+}
+
+/// Write `content` to `path` through a temporary file, like `write_toml_file`.
+pub fn write_file(path: &Path, content: &[u8]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        if !dir.as_os_str().is_empty() {
+            fs::create_dir_all(dir)?;
+        }
+    }
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, content)?;
+    fs::rename(&tmp_path, path)
+}
+
+pub fn to_unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn from_unix_secs(secs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
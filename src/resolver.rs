@@ -0,0 +1,17 @@
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+
+/// Resolve a peer hostname into socket addresses.
+pub trait Resolver: Send + Sync {
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>>;
+}
+
+/// Resolve by the system resolver, it blocks the caller until the lookup is done.
+#[derive(Clone, Debug, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        (host, port).to_socket_addrs().map(|addrs| addrs.collect())
+    }
+}
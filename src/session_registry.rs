@@ -0,0 +1,248 @@
+use crate::compression::Compression;
+use p2p::{ProtocolId, SessionId, SessionType};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::time::SystemTime;
+
+/// A session with a peer, opened by either side.
+#[derive(Clone, Debug)]
+pub struct SessionInfo {
+    pub id: SessionId,
+    pub ty: SessionType,
+    /// Remote address of the session.
+    pub addr: SocketAddr,
+    /// The address dialed for the session. An inbound session gets one if the node
+    /// is dialed again and the dial turns out to be a repeated connection.
+    pub dialed_addr: Option<SocketAddr>,
+    /// Hex encoded public key of the peer.
+    pub public_key: Option<String>,
+    pub connected_at: SystemTime,
+    /// Protocols open on the session, and the versions.
+    pub protocols: BTreeMap<ProtocolId, String>,
+    /// The codec negotiated to compress the transfer messages to the peer.
+    pub compression: Option<Compression>,
+    /// Add checksums to the transfer messages to the peer.
+    pub checksum: bool,
+    /// Split the long transfer messages to the peer into fragments.
+    pub fragment: bool,
+    // Counters of the transfer messages.
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    /// Frames from the peer dropped for a bad checksum.
+    pub checksum_failures: u64,
+}
+
+impl SessionInfo {
+    pub fn new(
+        id: SessionId,
+        ty: SessionType,
+        addr: SocketAddr,
+        public_key: Option<String>,
+    ) -> Self {
+        let dialed_addr = if ty == SessionType::Client {
+            Some(addr)
+        } else {
+            None
+        };
+        SessionInfo {
+            id,
+            ty,
+            addr,
+            dialed_addr,
+            public_key,
+            connected_at: SystemTime::now(),
+            protocols: BTreeMap::new(),
+            compression: None,
+            checksum: false,
+            fragment: false,
+            bytes_sent: 0,
+            bytes_received: 0,
+            messages_sent: 0,
+            messages_received: 0,
+            checksum_failures: 0,
+        }
+    }
+
+    pub fn is_inbound(&self) -> bool {
+        self.ty == SessionType::Server
+    }
+}
+
+/// All the open sessions of the node, inbound and outbound.
+#[derive(Debug, Default)]
+pub struct SessionRegistry {
+    sessions: HashMap<SessionId, SessionInfo>,
+    // Protocols opened before the session is known, the protocol handles and the service
+    // handle report to the nodes manager in no particular order.
+    pending_protocols: HashMap<SessionId, BTreeMap<ProtocolId, String>>,
+}
+
+impl SessionRegistry {
+    pub fn insert(&mut self, mut info: SessionInfo) {
+        if let Some(protocols) = self.pending_protocols.remove(&info.id) {
+            info.protocols.extend(protocols);
+        }
+        self.sessions.insert(info.id, info);
+    }
+
+    pub fn remove(&mut self, id: SessionId) -> Option<SessionInfo> {
+        self.pending_protocols.remove(&id);
+        self.sessions.remove(&id)
+    }
+
+    pub fn get(&self, id: SessionId) -> Option<&SessionInfo> {
+        self.sessions.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: SessionId) -> Option<&mut SessionInfo> {
+        self.sessions.get_mut(&id)
+    }
+
+    pub fn contains(&self, id: SessionId) -> bool {
+        self.sessions.contains_key(&id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &SessionInfo> {
+        self.sessions.values()
+    }
+
+    /// Sessions of one direction.
+    pub fn directed(&self, inbound: bool) -> impl Iterator<Item = &SessionInfo> {
+        self.sessions
+            .values()
+            .filter(move |info| info.is_inbound() == inbound)
+    }
+
+    /// The addresses dialed for the sessions.
+    pub fn dialed_addrs(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.sessions.values().filter_map(|info| info.dialed_addr)
+    }
+
+    pub fn is_dialed(&self, addr: &SocketAddr) -> bool {
+        self.dialed_addrs().any(|dialed| dialed == *addr)
+    }
+
+    pub fn open_protocol(&mut self, id: SessionId, proto_id: ProtocolId, version: String) {
+        let protocols = match self.sessions.get_mut(&id) {
+            Some(info) => &mut info.protocols,
+            None => self
+                .pending_protocols
+                .entry(id)
+                .or_insert_with(BTreeMap::new),
+        };
+        protocols.insert(proto_id, version);
+    }
+
+    pub fn close_protocol(&mut self, id: SessionId, proto_id: ProtocolId) {
+        if let Some(info) = self.sessions.get_mut(&id) {
+            info.protocols.remove(&proto_id);
+        } else if let Some(protocols) = self.pending_protocols.get_mut(&id) {
+            protocols.remove(&proto_id);
+        }
+    }
+
+    pub fn record_sent(&mut self, id: SessionId, bytes: usize, messages: usize) {
+        if let Some(info) = self.sessions.get_mut(&id) {
+            info.bytes_sent += bytes as u64;
+            info.messages_sent += messages as u64;
+        }
+    }
+
+    pub fn record_received(&mut self, id: SessionId, bytes: usize, messages: usize) {
+        if let Some(info) = self.sessions.get_mut(&id) {
+            info.bytes_received += bytes as u64;
+            info.messages_received += messages as u64;
+        }
+    }
+
+    pub fn record_checksum_failure(&mut self, id: SessionId) {
+        if let Some(info) = self.sessions.get_mut(&id) {
+            info.checksum_failures += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SessionInfo, SessionRegistry};
+    use p2p::SessionType;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn track_sessions() {
+        let outbound: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        let inbound: SocketAddr = "10.0.0.2:50002".parse().unwrap();
+        let mut registry = SessionRegistry::default();
+        registry.insert(SessionInfo::new(1, SessionType::Client, outbound, None));
+        registry.insert(SessionInfo::new(
+            2,
+            SessionType::Server,
+            inbound,
+            Some("abcd".to_owned()),
+        ));
+
+        assert_eq!(registry.len(), 2);
+        assert_eq!(registry.directed(true).count(), 1);
+        assert_eq!(registry.directed(false).count(), 1);
+        assert!(registry.is_dialed(&outbound));
+        assert!(!registry.is_dialed(&inbound));
+        assert_eq!(registry.get(2).unwrap().public_key, Some("abcd".to_owned()));
+
+        registry.open_protocol(1, 1, "0.1".to_owned());
+        registry.open_protocol(1, 2, "0.1".to_owned());
+        registry.close_protocol(1, 2);
+        assert_eq!(
+            registry
+                .get(1)
+                .unwrap()
+                .protocols
+                .keys()
+                .collect::<Vec<_>>(),
+            vec![&1]
+        );
+
+        registry.record_sent(1, 100, 1);
+        registry.record_sent(1, 50, 1);
+        registry.record_received(1, 10, 1);
+        registry.record_received(1, 30, 2);
+        registry.record_checksum_failure(1);
+        let info = registry.get(1).unwrap();
+        assert_eq!((info.bytes_sent, info.messages_sent), (150, 2));
+        assert_eq!((info.bytes_received, info.messages_received), (40, 3));
+        assert_eq!(info.checksum_failures, 1);
+
+        // Unknown sessions are ignored.
+        registry.record_received(3, 10, 1);
+        assert!(registry.remove(1).is_some());
+        assert!(!registry.is_dialed(&outbound));
+
+        // Protocols opened before the session are kept until it is inserted or removed.
+        registry.open_protocol(3, 1, "0.1".to_owned());
+        registry.open_protocol(3, 2, "0.1".to_owned());
+        registry.close_protocol(3, 2);
+        registry.insert(SessionInfo::new(3, SessionType::Client, outbound, None));
+        assert_eq!(
+            registry
+                .get(3)
+                .unwrap()
+                .protocols
+                .keys()
+                .collect::<Vec<_>>(),
+            vec![&1]
+        );
+        registry.open_protocol(4, 1, "0.1".to_owned());
+        assert!(registry.remove(4).is_none());
+        registry.insert(SessionInfo::new(4, SessionType::Server, inbound, None));
+        assert!(registry.get(4).unwrap().protocols.is_empty());
+    }
+}
@@ -15,7 +15,8 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 use crate::mq_client::{MqClient, PubMessage};
-use crate::node_manager::{BroadcastReq, NodesManagerClient, SingleTxReq};
+use crate::node_manager::{BroadcastReq, NodesManagerClient, SingleTxReq, UpdateScoreReq};
+use crate::peer_score::ScoreEvent;
 use crossbeam_channel;
 use crossbeam_channel::unbounded;
 use libproto::blockchain::{Block, Status};
@@ -46,6 +47,8 @@ pub struct Synchronizer {
     rand: ThreadRng,
     // Timer for each height processing
     remote_sync_time_out: Instant,
+    // The origin of the last sync request, and when it was sent
+    pending_sync_req: Option<(u32, Instant)>,
     /// local sync error
     local_sync_count: u8,
     sync_client: SynchronizerClient,
@@ -70,6 +73,7 @@ impl Synchronizer {
             block_lists: BTreeMap::new(),
             rand: thread_rng(),
             remote_sync_time_out: (Instant::now() - Duration::from_secs(SYNC_TIME_OUT)),
+            pending_sync_req: None,
             local_sync_count: 0,
             sync_client: client,
             msg_receiver: rx,
@@ -232,10 +236,19 @@ impl Synchronizer {
         self.is_synchronizing
     }
 
-    pub fn process_sync(&mut self, mut blocks: SyncResponse) {
+    pub fn process_sync(&mut self, mut blocks: SyncResponse, origin: u32) {
         let blocks = blocks.take_blocks();
         debug!("sync: process_sync: blocks len = {}", blocks.len());
 
+        if let Some((pending_origin, _)) = self.pending_sync_req {
+            if pending_origin == origin {
+                self.pending_sync_req = None;
+            }
+        }
+        if !blocks.is_empty() {
+            self.update_score(origin, ScoreEvent::UsefulSyncResponse);
+        }
+
         let mut heights = vec![];
         for block in blocks.into_iter() {
             heights.push(block.get_header().get_height());
@@ -278,10 +291,22 @@ impl Synchronizer {
         }
 
         if is_send {
+            // The last request is not responded in time, blame the node
+            if let Some((pending_origin, sent_at)) = self.pending_sync_req.take() {
+                if sent_at.elapsed().as_secs() > SYNC_TIME_OUT {
+                    self.update_score(pending_origin, ScoreEvent::SyncTimeout);
+                }
+            }
+            self.pending_sync_req = Some((origin, Instant::now()));
             self.sync_strategy(start_height, end_height, origin);
         }
     }
 
+    fn update_score(&self, origin: u32, event: ScoreEvent) {
+        self.nodes_mgr_client
+            .update_score(UpdateScoreReq::new(origin as usize, event));
+    }
+
     fn sync_strategy(&self, start_height: u64, end_height: u64, origin: u32) {
         //current height = 155,start_height = 156, end height = 160, to origin = 1
         debug!(
@@ -514,7 +539,7 @@ impl SynchronizerMessage {
             }
             routing_key!(Synchronizer >> SyncResponse) => {
                 if let Some(blocks) = msg.take_sync_response() {
-                    service.process_sync(blocks);
+                    service.process_sync(blocks, origin);
                 };
             }
             _ => {